@@ -7,9 +7,13 @@
 
 extern crate fitrs;
 extern crate tiff;
+extern crate argparse;
+extern crate rand;
 
-use std::env;
 use std::fmt;
+use std::fs;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use fitrs::{Fits, Hdu};
@@ -19,9 +23,15 @@ use pbr::ProgressBar;
 use tiff::decoder::{Decoder, DecodingResult};
 use tiff::ColorType;
 use scoped_threadpool::Pool;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::process;
 use std::f32::consts::PI;
+use argparse::{ArgumentParser, Store};
+use pore_favor::export::{self, ExportSettings};
+use pore_favor::npy::{self, Dtype, NpyArray};
+use pore_favor::split::{self, SplitLayout, SplitSettings};
+use rand::prelude::*;
 
 pub enum Direction {
     Right,
@@ -124,7 +134,7 @@ pub fn aug_img(img : &Vec<Vec<f32>>, dir : Direction) -> Vec<Vec<f32>> {
     img_blurred
 }
 
-/// Returns None
+/// Returns a Result
 /// Save a fits image
 /// # Arguments
 /// 
@@ -132,8 +142,11 @@ pub fn aug_img(img : &Vec<Vec<f32>>, dir : Direction) -> Vec<Vec<f32>> {
 /// * `height` - usize for the image height 
 /// * `width` - the width of the image as usize 
 /// * `filename` - A String - the filename to save
+/// * `object` - usize - the object the image was cut from
+/// * `split` - An Option of String - the split of the object, if any
 ///
-fn save_final_fits(img : &Vec<Vec<f32>>, height : usize, width : usize, filename : &String) {
+fn save_final_fits(img : &Vec<Vec<f32>>, height : usize, width : usize, filename : &String,
+    object : usize, split : &Option<String>) -> Result<(), Box<Error>> {
     let mut data : Vec<f32> = (0..height)
         .map(|i| (0..width).map(
                move |j| (i + j) as f32)).flatten().collect();
@@ -152,129 +165,303 @@ fn save_final_fits(img : &Vec<Vec<f32>>, height : usize, width : usize, filename
     primary_hdu.insert("NORMALISATION", "NONE");
     primary_hdu.insert("WIDTH", width as i32);
     primary_hdu.insert("HEIGHT", height as i32);
-    Fits::create(filename, primary_hdu).expect("Failed to create");  
+    primary_hdu.insert("OBJECT", object as i32);
+    if let Some(name) = split {
+        primary_hdu.insert("SPLIT", name.as_str());
+    }
+    Fits::create(filename, primary_hdu)?;
+    Ok(())
 }
 
-/// Returns None
-/// 
+/// How the cut out images are written. Fits gives each image its own
+/// file. Npy and Npz write them all as one [N, H, W] NumPy stack, and
+/// Npz adds where each image came from as arrays of their own.
+#[derive(Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Fits,
+    Npy,
+    Npz
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+    fn from_str(src: &str) -> Result<OutputFormat, ()> {
+        return match src {
+            "fits" => Ok(OutputFormat::Fits),
+            "npy" => Ok(OutputFormat::Npy),
+            "npz" => Ok(OutputFormat::Npz),
+            _ => Err(())
+        };
+    }
+}
+
+/// A cut out image and where it came from. Augment is 0 for the cut
+/// itself, then 1, 2 and 3 for the left, right and down turns.
+pub struct Crop {
+    count : usize,
+    object : usize,
+    split : Option<String>,
+    augment : usize,
+    x : usize,
+    y : usize,
+    width : usize,
+    height : usize,
+    image : Vec<Vec<f32>>
+}
+
+/// Returns a String - the directory, with a trailing slash, that the
+/// images of a split go in, or nothing if they all go in one place.
 /// # Arguments
 /// 
+/// * `split` - An Option of String - the split, if any
+/// * `layout` - A SplitLayout - how the splits are laid out
 ///
+fn split_dir(split : &Option<String>, layout : SplitLayout) -> String {
+    match (split, layout) {
+        (Some(name), SplitLayout::Dirs) => format!("{}/", name),
+        _ => String::new()
+    }
+}
 
-fn find_extents(mask : &Vec<u16>, height : usize, width : usize, start : usize, end : usize) -> Vec<(usize, usize, usize, usize, usize)> {
-    let mut extents :  Vec<(usize, usize, usize, usize, usize)> = vec!();
+/// Returns a Result
+/// Save a cut out image as FITS, unless it goes in a NumPy stack,
+/// and, if asked, export it as well.
+/// # Arguments
+/// 
+/// * `crop` - A Crop - the image and where it came from
+/// * `image_size` - usize for the width and height of the image
+/// * `format` - An OutputFormat - whether to write the FITS file
+/// * `layout` - A SplitLayout - whether the split picks the directory
+/// * `export` - An ExportSettings - whether and how to export
+///
+fn save_image(crop : &Crop, image_size : usize, format : OutputFormat, layout : SplitLayout,
+    export : &ExportSettings) -> Result<(), Box<Error>> {
+    let img = &crop.image;
+    let dir = split_dir(&crop.split, layout);
+
+    if format == OutputFormat::Fits {
+        let fidx = format!("{}image_{:06}.fits", dir, crop.count);
+        save_final_fits(img, image_size, image_size, &fidx, crop.object, &crop.split)
+            .map_err(|e| format!("Error writing {}: {}", fidx, e))?;
+    }
 
-    for _i in start..end {
-        // Now create the image we shall save as a fits
-        let new_image : Vec<Vec<f32>> = vec!();
+    if export.format.is_some() {
+        let eidx = format!("{}image_{:06}.{}", dir, crop.count, export.extension());
+        export::save_export(img, image_size, image_size, &eidx, export)
+            .map_err(|e| format!("Error writing {}: {}", eidx, e))?;
+    }
+    Ok(())
+}
 
-        let mut min_x = width;
-        let mut min_y = height;
-        let mut max_x = 0;
-        let mut max_y = 0;
+/// Returns an Option of an extent - the width, height, left and top of
+/// one object, and the object itself. None if the object is not in the mask.
+///
+/// # Arguments
+///
+/// * `mask` - A Vec of u16 - the object mask
+/// * `height` - usize - the mask height
+/// * `width` - usize - the mask width
+/// * `object` - usize - the object to look for
+///
+fn find_extent(mask : &Vec<u16>, height : usize, width : usize, object : usize) -> Option<(usize, usize, usize, usize, usize)> {
+    let mut min_x = width;
+    let mut min_y = height;
+    let mut max_x = 0;
+    let mut max_y = 0;
 
-        for y in 0..height {
-            for x in 0..width {
-                let pos = y * width + x;
+    for y in 0..height {
+        for x in 0..width {
+            let pos = y * width + x;
 
-                if mask[pos] as usize == _i {
-                    if x < min_x { min_x = x; }
-                    if x > max_x { max_x = x; }
-                    if y < min_y { min_y = y; }
-                    if y > max_y { max_y = y; }
-                }
+            if mask[pos] as usize == object {
+                if x < min_x { min_x = x; }
+                if x > max_x { max_x = x; }
+                if y < min_y { min_y = y; }
+                if y > max_y { max_y = y; }
             }
         }
-        
-        let w = max_x - min_x;
-        let h = max_y - min_y;
-        extents.push((w, h, min_x, min_y, _i));
     }
 
-    extents
+    if min_x > max_x { return None; }
+    let w = max_x - min_x;
+    let h = max_y - min_y;
+    Some((w, h, min_x, min_y, object))
 }
 
 
-/// Returns None
-/// 
+/// Returns a Vec of Crop - the object cut out of the raw image, then
+/// turned left, right and down, numbered from `count`.
+///
 /// # Arguments
-/// 
 ///
+/// * `raw_image` - A Vec of f32 - the raw image
+/// * `image_size` - usize for the width and height of the cut outs
+/// * `raw_width` - usize - the raw image width
+/// * `extent` - the width, height, left, top and object to cut out
+/// * `count` - usize - the number of the first image
+/// * `gauss` - f32 - the sigma of the blur, 0 for none
+/// * `split` - An Option of String - the split of the object
+///
+fn cut_image(raw_image : &Vec<f32>, image_size : usize, raw_width : usize, extent : (usize, usize, usize, usize, usize), count : usize,
+    gauss: f32, split : Option<String>) -> Vec<Crop> {
+    // Now create the image we shall save as a fits
+    let (w, h, xstart, ystart, ridx) = extent;
+    let mut new_image : Vec<Vec<f32>> = vec!();
+
+    // Allocate 0s
+    for _y in 0..image_size {
+        let mut row : Vec<f32> = vec!();
 
-fn cut_image(raw_image : &Vec<f32>, image_size : usize, raw_width : usize, extents : &Vec<(usize, usize, usize, usize, usize)>, start : usize, end : usize, gauss: f32)  -> usize {
-    let mut count = start * 4;
-
-    for _i in start..end {
-        let idx = _i;
-        // Now create the image we shall save as a fits
-        let w = extents[idx].0;
-        let h = extents[idx].1;
-        let xstart = extents[idx].2;
-        let ystart = extents[idx].3;
-        let ridx = extents[idx].4;
-        let mut new_image : Vec<Vec<f32>> = vec!();
-        
-        // Allocate 0s
-        for _y in 0..image_size {
-            let mut row : Vec<f32> = vec!();
-
-            for _x in 0..image_size {
-                row.push(0.0);
-            }
-
-            new_image.push(row);
+        for _x in 0..image_size {
+            row.push(0.0);
         }
 
-        for y in 0..h {
+        new_image.push(row);
+    }
+
+    for y in 0..h {
 
-            for x in 0..w {
-                let raw_pos = (y + ystart) * raw_width + x + xstart;
+        for x in 0..w {
+            let raw_pos = (y + ystart) * raw_width + x + xstart;
 
-                if raw_pos < raw_image.len() {
-                    new_image[y][x] = raw_image[raw_pos];
-                }
+            if raw_pos < raw_image.len() {
+                new_image[y][x] = raw_image[raw_pos];
             }
         }
-        
-        // Gaussian blur on top
-        if gauss != 0.0 {
-            new_image = gauss_blur(&new_image, gauss);
+    }
+
+    // Gaussian blur on top
+    if gauss != 0.0 {
+        new_image = gauss_blur(&new_image, gauss);
+    }
+
+    println!("New Image {}, {}, {}, {}, {}", ridx, xstart, ystart, w, h);
+
+    // now Aug 3 times
+    let left = aug_img(&new_image, Direction::Left);
+    let right = aug_img(&new_image, Direction::Right);
+    let down = aug_img(&new_image, Direction::Down);
+
+    vec![new_image, left, right, down].into_iter().enumerate().map(|(augment, img)| {
+        Crop {
+            count : count + augment,
+            object : ridx,
+            split : split.clone(),
+            augment : augment,
+            x : xstart,
+            y : ystart,
+            width : w,
+            height : h,
+            image : img
         }
+    }).collect()
+}
 
-        let mut fidx = format!("image_{:06}.fits", count as usize);
-        println!("New Image {}, {}, {}, {}, {}", ridx, xstart, ystart, w, h);
-        save_final_fits(&new_image, image_size, image_size, &fidx);
-        count = count + 1;
+/// Returns a Result
+/// Write the cut outs as they come in from the cutting threads and
+/// tell `done` about each object once it is written. NumPy stacks are
+/// written at the end, once every image is in, so their objects only
+/// count as done once the stacks are saved.
+/// # Arguments
+///
+/// * `rx` - A Receiver of Vec of Crop - the images of one object at a time
+/// * `done` - A Sender - where each written object goes
+/// * `image_size` - usize for the width and height of the images
+/// * `format` - An OutputFormat - FITS files or a stack
+/// * `dtype` - A Dtype - the pixel type of a stack
+/// * `layout` - A SplitLayout - whether the split picks the directory
+/// * `export` - An ExportSettings - whether and how to export
+///
+fn write_crops(rx : Receiver<Vec<Crop>>, done : &Sender<Result<usize, String>>, image_size : usize, format : OutputFormat,
+    dtype : Dtype, layout : SplitLayout, export : &ExportSettings) -> Result<(), String> {
+    let mut crops : Vec<Crop> = vec!();
+    let mut objects : Vec<usize> = vec!();
+
+    for object in rx.iter() {
+        for crop in &object {
+            save_image(crop, image_size, format, layout, export).map_err(|e| e.to_string())?;
+        }
+        if format == OutputFormat::Fits {
+            let _ = done.send(Ok(object[0].object));
+        } else {
+            objects.push(object[0].object);
+            crops.extend(object);
+        }
+    }
 
-        // now Aug 3 times
-        let left = aug_img(&new_image, Direction::Left);
-        fidx = format!("image_{:06}.fits", count as usize);
-        count = count + 1;
-        save_final_fits(&left, image_size, image_size, &fidx);
+    if format != OutputFormat::Fits {
+        crops.sort_by_key(|c| c.count);
+        // With a directory per split, each gets a stack of its own
+        let mut stacks : BTreeMap<String, Vec<&Crop>> = BTreeMap::new();
+        for crop in &crops {
+            stacks.entry(split_dir(&crop.split, layout)).or_insert(vec![]).push(crop);
+        }
+        for (dir, stack) in &stacks {
+            save_stack(stack, image_size, format, dtype, dir).map_err(|e| e.to_string())?;
+        }
+        for object in objects {
+            let _ = done.send(Ok(object));
+        }
+    }
+    Ok(())
+}
 
-        let right = aug_img(&new_image, Direction::Right);
-        fidx = format!("image_{:06}.fits", count as usize);
-        count = count + 1;
-        save_final_fits(&right, image_size, image_size, &fidx);
+/// Returns a Result
+/// Save the cut out images as one NumPy stack, in the order of their
+/// numbers. An npz also gets where each image came from.
+/// # Arguments
+/// 
+/// * `crops` - A Vec of Crop - the images, in order
+/// * `image_size` - usize for the width and height of the images
+/// * `format` - An OutputFormat - npy or npz
+/// * `dtype` - A Dtype - the pixel type of the stack
+/// * `dir` - A str - the directory to write to, with a trailing slash
+///
+fn save_stack(crops : &Vec<&Crop>, image_size : usize, format : OutputFormat, dtype : Dtype, dir : &str) -> Result<(), Box<Error>> {
+    let images : Vec<&Vec<Vec<f32>>> = crops.iter().map(|c| &c.image).collect();
+    let stack = NpyArray::images(&images, image_size, image_size, dtype);
+    let filename = format!("{}images.{}", dir, if format == OutputFormat::Npy { "npy" } else { "npz" });
 
-        let down = aug_img(&new_image, Direction::Down);
-        fidx = format!("image_{:06}.fits", count as usize);
-        count = count + 1;
-        save_final_fits(&down, image_size, image_size, &fidx);
+    let result = if format == OutputFormat::Npy {
+        npy::save_npy(&stack, &filename)
+    } else {
+        let column = |value : fn(&Crop) -> usize| -> NpyArray {
+            NpyArray::ints(&crops.iter().map(|c| value(c) as i64).collect::<Vec<_>>())
+        };
+        let mut arrays = vec![
+            (String::from("images"), stack),
+            (String::from("image"), column(|c| c.count)),
+            (String::from("object"), column(|c| c.object)),
+            (String::from("augment"), column(|c| c.augment)),
+            (String::from("x"), column(|c| c.x)),
+            (String::from("y"), column(|c| c.y)),
+            (String::from("width"), column(|c| c.width)),
+            (String::from("height"), column(|c| c.height))
+        ];
+        if crops.iter().any(|c| c.split.is_some()) {
+            let names : Vec<String> = crops.iter().map(|c| c.split.clone().unwrap_or(String::new())).collect();
+            arrays.push((String::from("split"), NpyArray::texts(&names)));
+        }
+        npy::save_npz(&arrays, &filename)
+    };
 
-    }
-    end - start
+    result.map_err(|e| From::from(format!("Error writing {}: {}", filename, e)))
 }
 
 
-/// Returns None
-/// 
+/// Returns a Result - the first error any thread hits, which also
+/// stops the others. The threads take one object at a time off a
+/// shared queue, so big objects do not hold up the rest, and one
+/// writer thread does all the writing.
+///
 /// # Arguments
-/// 
+///
 
-fn process_mask(mask : &Vec<u16>, raw: &Vec<f32>, height : usize, width : usize, nthreads : u32, gauss: f32) {
+fn process_mask(mask : &Vec<u16>, raw: &Vec<f32>, height : usize, width : usize, nthreads : u32, gauss: f32,
+    format : OutputFormat, dtype : Dtype, split_settings : &SplitSettings, split_seed : u64, export : &ExportSettings)
+    -> Result<(), Box<Error>> {
     let mut total_objs : u32 = 0;
-    
+
     for val in mask {
         if *val > total_objs as u16 {
             total_objs = *val as u32;
@@ -283,40 +470,36 @@ fn process_mask(mask : &Vec<u16>, raw: &Vec<f32>, height : usize, width : usize,
 
     println!("Number of objects {}", total_objs);
 
-    let mut progress : i32 = 0;
-    let mut pool = Pool::new(nthreads);
-    let truns = (total_objs / nthreads) as u32;
-    let spare = (total_objs % nthreads) as u32;
+    let mut pool = Pool::new(nthreads + 1);
     let mut extents : Vec<(usize, usize, usize, usize, usize)> = vec!();
     let (tx, rx) = channel();
+    // Each thread gets its own sender, so the channel closes once they all finish
+    let senders : Vec<_> = (0..nthreads).map(|_| tx.clone()).collect();
+    drop(tx);
+    // The next object to look for, starting at 1 as 0 is the background
+    let next = AtomicUsize::new(1);
+    let next = &next;
 
-    // Break the range into groups for each thread
     pool.scoped(|scoped| {
 
-        for _t in 0..nthreads {
-            let tx = tx.clone();
-            let mut start : usize = (_t * truns) as usize;
-            if start == 0 { start = 1; }
-            let mut end = ((_t + 1)  * truns) as usize;
-            if _t == nthreads - 1 { end = end + (spare as usize); }
-           
-            scoped.execute( move || { 
-                ///println!("Start {} - end {}", start, end);
-                let textents = find_extents(mask, height, width, start, end);
-                tx.send(textents).unwrap();
+        for tx in senders.into_iter() {
+            scoped.execute( move || {
+                loop {
+                    let object = next.fetch_add(1, Ordering::SeqCst);
+                    if object > total_objs as usize { break; }
+                    let _ = tx.send(find_extent(mask, height, width, object));
+                }
             });
         }
-    });
 
-    while progress < nthreads as i32 {
-        match rx.try_recv() {
-            Ok(_a) => {
-                progress = progress + 1;
-                println!("Progress");
-                for e in _a { extents.push(e); }
-            }, Err(_e) => {}
+        for (done, extent) in rx.iter().enumerate() {
+            println!("Progress {} / {}", done + 1, total_objs);
+            if let Some(e) = extent { extents.push(e); }
         }
-    }
+    });
+
+    // The threads finish in any order, but the image numbers should not
+    extents.sort_by_key(|e| e.4);
 
     let mut max_h : usize = 0;
     let mut max_w : usize = 0;
@@ -333,53 +516,125 @@ fn process_mask(mask : &Vec<u16>, raw: &Vec<f32>, height : usize, width : usize,
         max_dim = max_h;
     }
 
-    let (tx2, rx2) = channel();
+    // Whole objects go to a split, so their turns stay together
+    let mut splits : BTreeMap<i64, String> = BTreeMap::new();
+    if split_settings.enabled() {
+        let keys : Vec<i64> = extents.iter().map(|e| e.4 as i64).collect();
+        splits = split::assign(&keys, split_settings, split_seed)
+            .map_err(|e| format!("Error splitting the objects: {}", e))?;
+        for name in split::split_names(splits.values().cloned().collect()) {
+            println!("Split {} : {} objects", name, splits.values().filter(|s| **s == name).count());
+            if split_settings.layout == SplitLayout::Dirs {
+                fs::create_dir_all(&name).map_err(|e| format!("Error creating {}: {}", name, e))?;
+            }
+        }
+        split::write_splits(&splits, "object", Path::new("splits.csv"))
+            .map_err(|e| format!("Error writing the splits: {}", e))?;
+    }
+    let layout = split_settings.layout;
+    let splits = &splits;
+    let extents = &extents;
+
+    // The cutting threads send to the writer, which tells us as each object is written
+    let (tx2, rx2) = sync_channel(nthreads as usize * 2);
+    let (done_tx, done_rx) = channel();
+    let senders : Vec<_> = (0..nthreads).map(|_| tx2.clone()).collect();
+    drop(tx2);
+    // Set on the first error so the other threads stop early
+    let cancel = AtomicBool::new(false);
+    let cancel = &cancel;
+    let next = AtomicUsize::new(0);
+    let next = &next;
+    let mut failure : Option<String> = None;
+
     // Now cut up the image into smaller images taking the max extent
     pool.scoped(|scoped| {
 
-        for _t in 0..nthreads {
-            let tx2 = tx2.clone();
-            let extents = extents.clone();
-            let mut start : usize = (_t * truns) as usize;
-            let mut end = ((_t + 1) * truns) as usize;
-            if _t == nthreads - 1 { end = end + (spare as usize) - 1; }
-           
-            scoped.execute( move || { 
-                let done = cut_image(raw, max_dim, width, &extents, start, end, gauss);
-                tx2.send(done).unwrap();
+        for tx2 in senders.into_iter() {
+            scoped.execute( move || {
+                loop {
+                    let idx = next.fetch_add(1, Ordering::SeqCst);
+                    // Another thread failed, so there is no point going on
+                    if idx >= extents.len() || cancel.load(Ordering::SeqCst) { break; }
+                    let split = splits.get(&(extents[idx].4 as i64)).cloned();
+                    let crops = cut_image(raw, max_dim, width, extents[idx], idx * 4, gauss, split);
+                    if tx2.send(crops).is_err() { break; }
+                }
             });
         }
-    });
 
-    while progress < nthreads as i32 {
-        match rx2.try_recv() {
-            Ok(_a) => {
-                progress = progress + 1;
-                println!("Progress");
-            }, Err(_e) => {}
+        scoped.execute( move || {
+            if let Err(e) = write_crops(rx2, &done_tx, max_dim, format, dtype, layout, export) {
+                cancel.store(true, Ordering::SeqCst);
+                let _ = done_tx.send(Err(e));
+            }
+        });
+
+        let mut done = 0;
+        for _a in done_rx.iter() {
+            match _a {
+                Ok(_) => {
+                    done = done + 1;
+                    println!("Progress {} / {}", done, extents.len());
+                },
+                Err(e) => failure = Some(e)
+            }
         }
-    }
-
+    });
 
+    if let Some(e) = failure {
+        return Err(From::from(e));
+    }
+    Ok(())
 }
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
-    
-    if args.len() < 4 {
-        println!("Usage: ilastic <path to raw tiff> <path to class tiff> <num threads> <optional: gauss blur>"); 
-        process::exit(1);
+    let mut raw_path = String::new();
+    let mut obj_path = String::new();
+    let mut nthreads : u32 = 1;
+    let mut gauss : f32 = 0.0;
+    let mut format = OutputFormat::Fits;
+    let mut dtype = Dtype::Float32;
+    let mut split_settings = SplitSettings::new();
+    let mut export_settings = ExportSettings::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Cut the objects found by ilastik out of a large tiff.");
+        ap.refer(&mut raw_path)
+            .add_argument("raw", Store, "Path to the raw tiff")
+            .required();
+        ap.refer(&mut obj_path)
+            .add_argument("objects", Store, "Path to the object identity tiff")
+            .required();
+        ap.refer(&mut nthreads)
+            .add_argument("threads", Store, "Number of threads")
+            .required();
+        ap.refer(&mut gauss)
+            .add_argument("gauss", Store, "Sigma of the Gaussian blur, 0 for none (default: 0)");
+        ap.refer(&mut format)
+            .add_option(&["--format"], Store,
+            "Write each image to its own FITS file, or all of them as one NumPy stack: fits, npy or npz (default: fits)");
+        ap.refer(&mut dtype)
+            .add_option(&["--dtype"], Store,
+            "Pixel type of the npy and npz stacks: float32 or float16 (default: float32)");
+        export::add_options(&mut ap, &mut export_settings);
+        split::add_options(&mut ap, &mut split_settings);
+        ap.parse_args_or_exit();
     }
 
-    let raw_tiff_path = Path::new(&args[1]);
-    let obj_tiff_path = Path::new(&args[2]);
-    let nthreads = &args[3].parse::<u32>().unwrap();
-    let mut gauss:f32 = 0.0;
-
-    if args.len() == 5 {
-        gauss = args[4].parse::<f32>().unwrap();
+    if let Err(e) = export_settings.check().and_then(|_| split_settings.check()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    let split_seed = split_settings.seed.unwrap_or_else(|| thread_rng().gen::<u64>());
+    if split_settings.enabled() {
+        println!("Split seed: {}", split_seed);
     }
 
+    let raw_tiff_path = Path::new(&raw_path);
+    let obj_tiff_path = Path::new(&obj_path);
+
     let img_file_raw = File::open(raw_tiff_path).expect("Cannot find test image!");
     let mut decoder_raw = Decoder::new(img_file_raw).expect("Cannot create decoder");
 
@@ -395,7 +650,11 @@ fn main() {
 
         if let DecodingResult::U16(img_res_obj) = decoder_obj.read_image().unwrap() {
             println!("Obj Image Loaded.");
-            process_mask(&img_res_obj, &img_res_raw, 1280, 1280, *nthreads, gauss);
+            if let Err(e) = process_mask(&img_res_obj, &img_res_raw, 1280, 1280, nthreads, gauss, format, dtype,
+                &split_settings, split_seed, &export_settings) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
         }
 
     } else {
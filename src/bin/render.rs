@@ -13,13 +13,14 @@ extern crate fitrs;
 extern crate rand_distr;
 extern crate ndarray;
 extern crate csv;
+extern crate argparse;
 
-use std::env;
 use std::fmt;
 use rand::prelude::*;
+use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use fitrs::{Fits, Hdu};
+use std::io::{BufRead, BufReader, Write};
+use fitrs::{Fits, Hdu, HeaderValue};
 use rand_distr::{Normal, Distribution};
 use std::process;
 use std::path::Path;
@@ -27,73 +28,723 @@ use std::error::Error;
 use rand::distributions::Uniform;
 use rand::Rng;
 use scoped_threadpool::Pool;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use pbr::ProgressBar;
 use ndarray::{Slice, SliceInfo, s, Array1};
-
-static WIDTH : u32 = 1280;
-static HEIGHT : u32 = 1280;
-static SHRINK : f32 = 0.95;
+use std::collections::{BTreeMap, HashMap};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
+use std::str::FromStr;
+use na::{Rotation3, Vector3, Quaternion, UnitQuaternion, Unit, DMatrix, DVector};
+use pore_favor::export::{self, ExportSettings};
+use pore_favor::npy::{self, Dtype, NpyArray};
+use pore_favor::split::{self, SplitLayout, SplitSettings};
 
 #[derive(Copy, Clone)]
 pub struct Point {
     x : f32,
-    y : f32
+    y : f32,
+    z : f32,
+    sigma : f32,
+    weight : f32,
+    photons : f32,
+    frame : u32
+}
+
+/// How we pick the random rotation for each image.
+/// Plane spins the model in the image plane only, Tilt adds a tilt
+/// of the model out of the image plane up to a maximum angle and
+/// Sphere draws a rotation uniformly from all 3D rotations.
+#[derive(Copy, Clone, PartialEq)]
+pub enum RotationMode {
+    Plane,
+    Tilt,
+    Sphere
+}
+
+impl FromStr for RotationMode {
+    type Err = ();
+    fn from_str(src: &str) -> Result<RotationMode, ()> {
+        return match src {
+            "plane" => Ok(RotationMode::Plane),
+            "tilt" => Ok(RotationMode::Tilt),
+            "sphere" => Ok(RotationMode::Sphere),
+            _ => Err(())
+        };
+    }
+}
+
+/// How the points become an image. Gauss sums a normalised Gaussian
+/// for each point, Hist counts the (weighted) points in each pixel,
+/// HistBlur blurs that histogram with the global sigma and Max gives
+/// each pixel the largest contribution of any one point's Gaussian.
+#[derive(Copy, Clone, PartialEq)]
+pub enum RenderMode {
+    Gauss,
+    Hist,
+    HistBlur,
+    Max
+}
+
+impl FromStr for RenderMode {
+    type Err = ();
+    fn from_str(src: &str) -> Result<RenderMode, ()> {
+        return match src {
+            "gauss" => Ok(RenderMode::Gauss),
+            "hist" => Ok(RenderMode::Hist),
+            "hist-blur" => Ok(RenderMode::HistBlur),
+            "max" => Ok(RenderMode::Max),
+            _ => Err(())
+        };
+    }
+}
+
+impl fmt::Display for RenderMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderMode::Gauss => write!(f, "GAUSS"),
+            RenderMode::Hist => write!(f, "HIST"),
+            RenderMode::HistBlur => write!(f, "HISTBLUR"),
+            RenderMode::Max => write!(f, "MAX")
+        }
+    }
+}
+
+/// How the images are written. Files gives each image its own FITS
+/// file. Cube stacks the images of a chunk into one 3D image and Mef
+/// gives each image of a chunk its own extension. Both of the packed
+/// FITS formats end with a table of the manifest rows, one per plane.
+/// Npy writes each chunk as one [N, H, W] NumPy stack and Npz adds
+/// the manifest columns to the stack as arrays of their own.
+#[derive(Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Files,
+    Cube,
+    Mef,
+    Npy,
+    Npz
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+    fn from_str(src: &str) -> Result<OutputFormat, ()> {
+        return match src {
+            "files" => Ok(OutputFormat::Files),
+            "cube" => Ok(OutputFormat::Cube),
+            "mef" => Ok(OutputFormat::Mef),
+            "npy" => Ok(OutputFormat::Npy),
+            "npz" => Ok(OutputFormat::Npz),
+            _ => Err(())
+        };
+    }
+}
+
+/// How the rotations of the augmented renders of a model are picked.
+/// Random draws a fresh rotation for each render. Even draws one
+/// rotation for the model and then spins it in equal steps about
+/// the view axis, so N renders cover the full turn.
+#[derive(Copy, Clone, PartialEq)]
+pub enum AngleMode {
+    Random,
+    Even
+}
+
+impl FromStr for AngleMode {
+    type Err = ();
+    fn from_str(src: &str) -> Result<AngleMode, ()> {
+        return match src {
+            "random" => Ok(AngleMode::Random),
+            "even" => Ok(AngleMode::Even),
+            _ => Err(())
+        };
+    }
+}
+
+/// Where the sigma of each point's Gaussian comes from. Global uses
+/// the one sigma for every point, Column uses the localisation
+/// precision of each point, converted to pixels.
+#[derive(Copy, Clone, PartialEq)]
+pub enum SigmaMode {
+    Global,
+    Column
+}
+
+impl FromStr for SigmaMode {
+    type Err = ();
+    fn from_str(src: &str) -> Result<SigmaMode, ()> {
+        return match src {
+            "global" => Ok(SigmaMode::Global),
+            "column" => Ok(SigmaMode::Column),
+            _ => Err(())
+        };
+    }
+}
+
+/// How far each point is moved at random before rendering, to mimic
+/// a second measurement of the same structure. Fixed uses one sigma
+/// for every point, Precision uses each point's localisation
+/// precision, falling back to the fixed sigma where there is none.
+#[derive(Copy, Clone, PartialEq)]
+pub enum JitterMode {
+    None,
+    Fixed,
+    Precision
+}
+
+impl fmt::Display for JitterMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JitterMode::None => write!(f, "NONE"),
+            JitterMode::Fixed => write!(f, "FIXED"),
+            JitterMode::Precision => write!(f, "PRECISION")
+        }
+    }
+}
+
+/// What each point's Gaussian is weighted by. Unit gives every point
+/// the same mass, Column uses a numeric column such as the photon count.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Weighting {
+    Unit,
+    Column
+}
+
+impl FromStr for Weighting {
+    type Err = ();
+    fn from_str(src: &str) -> Result<Weighting, ()> {
+        return match src {
+            "unit" => Ok(Weighting::Unit),
+            "column" => Ok(Weighting::Column),
+            _ => Err(())
+        };
+    }
+}
+
+/// How the weights are normalised within each model. Sum makes the
+/// weights add up to one, Mean makes them average one (so the total
+/// matches unit weighting) and Max scales the largest to one.
+#[derive(Copy, Clone, PartialEq)]
+pub enum WeightNorm {
+    None,
+    Sum,
+    Mean,
+    Max
+}
+
+impl FromStr for WeightNorm {
+    type Err = ();
+    fn from_str(src: &str) -> Result<WeightNorm, ()> {
+        return match src {
+            "none" => Ok(WeightNorm::None),
+            "sum" => Ok(WeightNorm::Sum),
+            "mean" => Ok(WeightNorm::Mean),
+            "max" => Ok(WeightNorm::Max),
+            _ => Err(())
+        };
+    }
+}
+
+impl fmt::Display for WeightNorm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WeightNorm::None => write!(f, "NONE"),
+            WeightNorm::Sum => write!(f, "SUM"),
+            WeightNorm::Mean => write!(f, "MEAN"),
+            WeightNorm::Max => write!(f, "MAX")
+        }
+    }
+}
+
+/// The settings for estimating the stage drift. Distances are in the
+/// units of the CSV (usually nm).
+pub struct DriftSettings {
+    windows : usize,
+    pixel : f32,
+    max_shift : f32,
+    degree : usize
+}
+
+/// The drift over the acquisition - a polynomial in time for each
+/// axis. Frames are mapped onto -1 to 1 to keep the fit well
+/// conditioned.
+pub struct DriftCurve {
+    fmin : u32,
+    fmax : u32,
+    cx : Vec<f64>,
+    cy : Vec<f64>,
+    cz : Vec<f64>
+}
+
+impl DriftCurve {
+    /// Returns the drift in x, y and z at a frame.
+    pub fn at(&self, frame : u32) -> (f32, f32, f32) {
+        let mut t = 0.0;
+        if self.fmax > self.fmin {
+            t = 2.0 * (frame as f64 - self.fmin as f64) / (self.fmax - self.fmin) as f64 - 1.0;
+        }
+        (eval_polynomial(&self.cx, t) as f32,
+         eval_polynomial(&self.cy, t) as f32,
+         eval_polynomial(&self.cz, t) as f32)
+    }
+}
+
+/// The settings that apply to every image we render.
+#[derive(Clone)]
+pub struct RenderSettings {
+    sigma : f32,
+    sigma_mode : SigmaMode,
+    sigma_mult : f32,
+    sigma_min : Option<f32>,
+    sigma_max : Option<f32>,
+    weight_name : String,
+    weight_norm : WeightNorm,
+    scale : f32,
+    max_points : usize,
+    keep_fraction : f32,
+    draws : u32,
+    rotation : RotationMode,
+    max_tilt : f32,
+    augments : u32,
+    angles : AngleMode,
+    jitter_mode : JitterMode,
+    jitter : f32,
+    mode : RenderMode,
+    format : OutputFormat,
+    chunk_size : usize,
+    dtype : Dtype,
+    export : ExportSettings,
+    splat_error : f32,
+    width : u32,
+    height : u32,
+    shrink : f32,
+    seed : u64,
+    source : String,
+    resume : bool
+}
+
+/// What we need to trace one rendered image back to its model and
+/// to undo the transform that placed the model in the image.
+pub struct ImageInfo {
+    index : usize,
+    id : i64,
+    seed : u64,
+    draw : u32,
+    augment : u32,
+    jitter : f32,
+    rotation : Rotation3<f32>,
+    centre : (f32, f32, f32),
+    npoints : usize
+}
+
+/// One row of the manifest - an image, where it came from and
+/// a few statistics so loaders need not open the image.
+pub struct ManifestRow {
+    file : String,
+    plane : Option<usize>,
+    block : Option<usize>,
+    split : Option<String>,
+    info : ImageInfo,
+    sigma : f32,
+    total : f32,
+    peak : f32,
+    mean : f32,
+    sd : f32,
+    nonzero : usize
+}
+
+/// A value in the manifest. JSON needs to know which are numbers.
+pub enum ManifestValue {
+    Int(i64),
+    Real(f32),
+    Text(String),
+    Missing
+}
+
+impl fmt::Display for ManifestValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestValue::Int(n) => write!(f, "{}", n),
+            ManifestValue::Real(r) => write!(f, "{}", r),
+            ManifestValue::Text(t) => write!(f, "{}", t),
+            ManifestValue::Missing => Ok(())
+        }
+    }
+}
+
+impl ManifestRow {
+    /// Returns a Vec of the column names and values, in the manifest's order.
+    fn fields(&self) -> Vec<(&'static str, ManifestValue)> {
+        let q = UnitQuaternion::from_rotation_matrix(&self.info.rotation);
+        let (angle, axis) = signed_rotation(&self.info.rotation);
+        vec![
+            ("file", ManifestValue::Text(self.file.clone())),
+            ("plane", match self.plane {
+                Some(p) => ManifestValue::Int(p as i64),
+                None => ManifestValue::Missing
+            }),
+            ("block", match self.block {
+                Some(b) => ManifestValue::Int(b as i64),
+                None => ManifestValue::Missing
+            }),
+            ("split", match &self.split {
+                Some(s) => ManifestValue::Text(s.clone()),
+                None => ManifestValue::Missing
+            }),
+            ("model_index", ManifestValue::Int(self.info.index as i64)),
+            ("model_id", ManifestValue::Int(self.info.id)),
+            // Seeds overflow the doubles most JSON readers use
+            ("seed", ManifestValue::Text(self.info.seed.to_string())),
+            ("draw", ManifestValue::Int(self.info.draw as i64)),
+            ("augment", ManifestValue::Int(self.info.augment as i64)),
+            ("jitter", ManifestValue::Real(self.info.jitter)),
+            ("angle", ManifestValue::Real(angle)),
+            ("axis_x", ManifestValue::Real(axis.0)),
+            ("axis_y", ManifestValue::Real(axis.1)),
+            ("axis_z", ManifestValue::Real(axis.2)),
+            ("quat_w", ManifestValue::Real(q.w)),
+            ("quat_x", ManifestValue::Real(q.i)),
+            ("quat_y", ManifestValue::Real(q.j)),
+            ("quat_z", ManifestValue::Real(q.k)),
+            ("centre_x", ManifestValue::Real(self.info.centre.0)),
+            ("centre_y", ManifestValue::Real(self.info.centre.1)),
+            ("centre_z", ManifestValue::Real(self.info.centre.2)),
+            ("sigma", ManifestValue::Real(self.sigma)),
+            ("points", ManifestValue::Int(self.info.npoints as i64)),
+            ("sum", ManifestValue::Real(self.total)),
+            ("max", ManifestValue::Real(self.peak)),
+            ("mean", ManifestValue::Real(self.mean)),
+            ("sd", ManifestValue::Real(self.sd)),
+            ("nonzero", ManifestValue::Int(self.nonzero as i64))
+        ]
+    }
+}
+
+impl RenderSettings {
+    /// Returns the f32 that takes a point from CSV units to pixels.
+    /// Each unit of the global scale spans the shorter side of the
+    /// image, less the margin.
+    pub fn scalar(&self) -> f32 {
+        self.scale * (self.width.min(self.height) as f32) * self.shrink
+    }
+
+    /// Returns the u32 number of images rendered for each model -
+    /// every augment of every draw.
+    pub fn renders(&self) -> u32 {
+        self.draws.max(1) * self.augments.max(1)
+    }
+
+    /// Returns the usize number of points to keep out of a model of
+    /// `len` points - the smaller of the count and the fraction.
+    pub fn keep_count(&self, len : usize) -> usize {
+        let mut keep = len;
+        if self.max_points > 0 { keep = keep.min(self.max_points); }
+        if self.keep_fraction < 1.0 {
+            keep = keep.min((len as f32 * self.keep_fraction.max(0.0)).round() as usize);
+        }
+        keep
+    }
+}
+
+/// A model is all the points that share an ID in the CSV file -
+/// usually a single pore. We keep the original ID and the index in
+/// the parsed list so we can trace a model back to the table it
+/// came from and to the accepted list. The centre is only set when
+/// the model is part of a larger one (e.g. a time block) and should
+/// be centred in the same place as the whole. The split is set when
+/// the models are split into train, val and test.
+#[derive(Clone)]
+pub struct Model {
+    index : usize,
+    id : i64,
+    points : Vec<Point>,
+    centre : Option<(f32, f32, f32)>,
+    split : Option<String>
+}
+
+/// The header names of the photon count, which is both the default
+/// weight and what blinking events are merged by.
+const PHOTON_ALIASES : &str = "intensity [photon],photons,phot,photon_count,intensity";
+
+/// The header names we look for when finding each column in the
+/// CSV file. Names are compared ignoring case and surrounding space.
+/// Earlier names are preferred over later ones. Optional columns
+/// listed in `needed` must be present as the settings rely on them.
+pub struct ColumnAliases {
+    x : Vec<String>,
+    y : Vec<String>,
+    z : Vec<String>,
+    id : Vec<String>,
+    sigma : Vec<String>,
+    weight : Vec<String>,
+    photons : Vec<String>,
+    frame : Vec<String>,
+    needed : Vec<&'static str>
+}
+
+impl ColumnAliases {
+    pub fn new() -> ColumnAliases {
+        ColumnAliases {
+            x : split_aliases("x [nm],xnm,x_nm,x"),
+            y : split_aliases("y [nm],ynm,y_nm,y"),
+            z : split_aliases("z [nm],znm,z_nm,z"),
+            id : split_aliases("id,particle,cluster,cluster_id,group"),
+            sigma : split_aliases("uncertainty_xy [nm],uncertainty [nm],uncertainty_xy,uncertainty,locprec,precision"),
+            weight : split_aliases(PHOTON_ALIASES),
+            photons : split_aliases(PHOTON_ALIASES),
+            frame : split_aliases("frame,frames,frame_number"),
+            needed : vec![]
+        }
+    }
+}
+
+/// The indices of the columns we use, found from the CSV header.
+/// The ID is optional - without it everything is a single model.
+/// Without a z column, all points sit at z = 0.
+pub struct Columns {
+    headers : csv::StringRecord,
+    x : usize,
+    y : usize,
+    z : Option<usize>,
+    id : Option<usize>,
+    sigma : Option<usize>,
+    weight : Option<usize>,
+    photons : Option<usize>,
+    frame : Option<usize>
+}
+
+/// Returns a Vec of String - the aliases in a comma separated list.
+/// # Arguments
+/// 
+/// * `list` - A str - comma separated header names
+///
+fn split_aliases(list : &str) -> Vec<String> {
+    list.split(',').map(|a| a.trim().to_string())
+        .filter(|a| a.len() > 0).collect()
+}
+
+/// Returns an Option of usize - the index of the first column whose
+/// header matches one of the aliases, trying the aliases in order.
+/// # Arguments
+/// 
+/// * `headers` - A StringRecord - the CSV header
+/// * `aliases` - A Vec of String - the names to look for
+///
+fn find_column(headers : &csv::StringRecord, aliases : &Vec<String>) -> Option<usize> {
+    for alias in aliases {
+        let alias = alias.to_lowercase();
+        for (idx, header) in headers.iter().enumerate() {
+            if header.trim().to_lowercase() == alias { return Some(idx); }
+        }
+    }
+    None
+}
+
+/// Returns a Result of usize - the index of a column we cannot do without.
+/// # Arguments
+/// 
+/// * `headers` - A StringRecord - the CSV header
+/// * `aliases` - A Vec of String - the names to look for
+/// * `name` - A str - what the column holds, for the error message
+///
+fn require_column(headers : &csv::StringRecord, aliases : &Vec<String>, name : &str) -> Result<usize, Box<Error>> {
+    match find_column(headers, aliases) {
+        Some(idx) => Ok(idx),
+        None => Err(From::from(format!(
+            "No {} column found. Looked for: {}. Header was: {}",
+            name, aliases.join(", "), headers.iter().collect::<Vec<&str>>().join(", "))))
+    }
+}
+
+/// Returns a Result of an Option of usize - the index of a column we
+/// can do without, unless it is one of the needed columns.
+/// # Arguments
+/// 
+/// * `headers` - A StringRecord - the CSV header
+/// * `aliases` - A ColumnAliases - the names to look for
+/// * `column` - A Vec of String - the names to look for this column
+/// * `name` - A str - what the column holds
+///
+fn optional_column(headers : &csv::StringRecord, aliases : &ColumnAliases, column : &Vec<String>,
+    name : &'static str) -> Result<Option<usize>, Box<Error>> {
+    if aliases.needed.contains(&name) {
+        return require_column(headers, column, name).map(Some);
+    }
+    Ok(find_column(headers, column))
+}
+
+/// Returns a Result of Columns - the columns we will read.
+/// Map the header onto the columns we need and report what we picked.
+/// # Arguments
+/// 
+/// * `headers` - A StringRecord - the CSV header
+/// * `aliases` - A ColumnAliases - the names to look for
+///
+fn map_columns(headers : &csv::StringRecord, aliases : &ColumnAliases) -> Result<Columns, Box<Error>> {
+    let columns = Columns {
+        headers : headers.clone(),
+        x : require_column(headers, &aliases.x, "x")?,
+        y : require_column(headers, &aliases.y, "y")?,
+        z : optional_column(headers, aliases, &aliases.z, "z")?,
+        id : optional_column(headers, aliases, &aliases.id, "id")?,
+        sigma : optional_column(headers, aliases, &aliases.sigma, "sigma")?,
+        weight : optional_column(headers, aliases, &aliases.weight, "weight")?,
+        photons : optional_column(headers, aliases, &aliases.photons, "photons")?,
+        frame : optional_column(headers, aliases, &aliases.frame, "frame")?
+    };
+
+    println!("Column x : '{}' ({})", &headers[columns.x], columns.x);
+    println!("Column y : '{}' ({})", &headers[columns.y], columns.y);
+    match columns.z {
+        Some(idx) => println!("Column z : '{}' ({})", &headers[idx], idx),
+        None => println!("No z column found (looked for {}). Points are 2D.",
+            aliases.z.join(", "))
+    }
+    match columns.id {
+        Some(idx) => println!("Column id : '{}' ({})", &headers[idx], idx),
+        None => println!("No id column found (looked for {}). All points are one model.",
+            aliases.id.join(", "))
+    }
+    match columns.sigma {
+        Some(idx) => println!("Column sigma : '{}' ({})", &headers[idx], idx),
+        None if aliases.sigma.len() == 0 => {},
+        None => println!("No sigma column found (looked for {}).", aliases.sigma.join(", "))
+    }
+    match columns.weight {
+        Some(idx) => println!("Column weight : '{}' ({})", &headers[idx], idx),
+        None if aliases.weight.len() == 0 => {},
+        None => println!("No weight column found (looked for {}).", aliases.weight.join(", "))
+    }
+    match columns.photons {
+        Some(idx) => println!("Column photons : '{}' ({})", &headers[idx], idx),
+        None => println!("No photons column found (looked for {}).", aliases.photons.join(", "))
+    }
+    match columns.frame {
+        Some(idx) => println!("Column frame : '{}' ({})", &headers[idx], idx),
+        None => println!("No frame column found (looked for {}).", aliases.frame.join(", "))
+    }
+    Ok(columns)
+}
+
+/// Returns a Result of f32 - a numeric field from a CSV row.
+/// # Arguments
+/// 
+/// * `record` - A StringRecord - the row
+/// * `idx` - A usize - the column to read
+/// * `line` - A usize - the line in the file, for the error message
+///
+fn parse_field(record : &csv::StringRecord, idx : usize, line : usize) -> Result<f32, Box<Error>> {
+    match record.get(idx) {
+        Some(field) => field.trim().parse::<f32>().map_err(|e| From::from(
+            format!("Line {}, column {}: cannot parse '{}' as a number: {}", line, idx, field, e))),
+        None => Err(From::from(format!("Line {} has no column {}", line, idx)))
+    }
+}
+
+/// Returns a Result of u32 - the frame number from a CSV row. Some
+/// tables write frames as floats (e.g. 12.0) so we take those too, as
+/// long as they are whole, not negative and fit in a u32.
+/// # Arguments
+///
+/// * `record` - A StringRecord - the row
+/// * `idx` - A usize - the column to read
+/// * `line` - A usize - the line in the file, for the error message
+///
+fn parse_frame(record : &csv::StringRecord, idx : usize, line : usize) -> Result<u32, Box<Error>> {
+    let field = match record.get(idx) {
+        Some(field) => field.trim(),
+        None => return Err(From::from(format!("Line {} has no column {}", line, idx)))
+    };
+    if let Ok(frame) = field.parse::<u32>() { return Ok(frame); }
+    match field.parse::<f64>() {
+        Ok(f) if f >= 0.0 && f.fract() == 0.0 && f <= u32::max_value() as f64 => Ok(f as u32),
+        _ => Err(From::from(format!("Line {}, column {}: the frame '{}' is not a whole number of 0 or more",
+            line, idx, field)))
+    }
 }
 
-/// Returns two f32 numbers - the extents in X and Y.
+/// Returns three f32 numbers - the extents in X, Y and Z.
 /// Go through all the models and find the extents. This gives
 /// us a global scale, we can use in the rendering.
 /// 
 /// # Arguments
 /// 
-/// * `models` - A Vector of Vectors of Point
+/// * `models` - A Vector of Model
 /// 
 
-fn find_extents ( models : &Vec<Vec<Point>> ) -> (f32, f32) {
+fn find_extents ( models : &Vec<Model> ) -> (f32, f32, f32) {
     let mut w : f32 = 0.0;
     let mut h : f32  = 0.0;
+    let mut d : f32  = 0.0;
 
     for model in models {
         let mut minx : f32 = 1e10;
         let mut miny : f32 = 1e10;
+        let mut minz : f32 = 1e10;
         let mut maxx : f32 = -1e10;
         let mut maxy : f32 = -1e10;
+        let mut maxz : f32 = -1e10;
         
-        for point in model {
+        for point in &model.points {
             if point.x < minx { minx = point.x; }
             if point.y < miny { miny = point.y; }
+            if point.z < minz { minz = point.z; }
             if point.x > maxx { maxx = point.x; }
             if point.y > maxy { maxy = point.y; }
+            if point.z > maxz { maxz = point.z; }
         }
         let tw = (maxx - minx).abs();
         let th = (maxy - miny).abs();
+        let td = (maxz - minz).abs();
         if tw > w { w = tw; }
         if th > h { h = th; }
+        if td > d { d = td; }
     }
 
-    (w, h)
+    (w, h, d)
+}
+
+/// Returns an f32 - the largest distance from the centre of a model
+/// to one of its points, over all the models. Rotating a model keeps
+/// this distance, so it bounds the model at any angle.
+/// # Arguments
+///
+/// * `models` - A Vec of Model
+/// * `use_z` - A bool - whether z counts, as it does once models turn out of the plane
+///
+fn find_radius(models : &Vec<Model>, use_z : bool) -> f32 {
+    let mut radius : f32 = 0.0;
+    for model in models {
+        let (cx, cy, cz) = match model.centre {
+            Some(c) => c,
+            None => find_centre(&model.points)
+        };
+        for p in &model.points {
+            let dz = if use_z { p.z - cz } else { 0.0 };
+            radius = radius.max(((p.x - cx).powi(2) + (p.y - cy).powi(2) + dz * dz).sqrt());
+        }
+    }
+    radius
 }
 
-/// Returns a Vector of Vectors of Points
+/// Returns a Vector of Model
 /// Filter models, by cutoff size thus far
 /// 
 /// # Arguments
 /// 
-/// * `models` - A Vec of Vectors of Point
+/// * `models` - A Vec of Model
 /// * `cutoff` - a u32 representing  the cutoff number of points
 /// * `accepted` - a Vec of usize representing the indices of the models we have accepted.
 ///
-fn filter_models(models : & Vec<Vec<Point>>, cutoff: u32, accepted : Vec<usize>) -> Vec<Vec<Point>> {
+fn filter_models(models : & Vec<Model>, cutoff: u32, accepted : Vec<usize>) -> Vec<Model> {
     let mut idx = 0;
-    let mut accepted_models : Vec<Vec<Point>> = vec!();
+    let mut accepted_models : Vec<Model> = vec!();
     while idx < models.len() {
         let mut remove : bool = false;
         if accepted.len() > 0 {
-            if !accepted.contains(&idx) { remove = true; }
+            if !accepted.contains(&models[idx].index) { remove = true; }
         }
 
-        if models[idx].len() < cutoff as usize { remove = true; }
+        if models[idx].points.len() < cutoff as usize { remove = true; }
         if !remove {
             let cc = models[idx].clone(); 
             accepted_models.push(cc);
@@ -105,22 +756,23 @@ fn filter_models(models : & Vec<Vec<Point>>, cutoff: u32, accepted : Vec<usize>)
 
 /// Returns statistics on the model as a tuple: mean, median, stddev, min and max
 /// Get some stats on the models, starting with the mean and
-/// median number of points
+/// median number of points. With no models, they are all 0.
 /// 
 /// # Arguments
 /// 
-/// * `models` - A Vec of Vectors of Point
+/// * `models` - A Vec of Model
 ///
-fn find_stats ( models : &Vec<Vec<Point>> ) -> (f32, u32, f32, u32, u32) {
+fn find_stats ( models : &Vec<Model> ) -> (f32, u32, f32, u32, u32) {
     let mut mean : f32 = 0.0;
     let mut median : u32 = 0;
     let mut min : u32 = 100000000;
     let mut max : u32 = 0;
     let mut sd : f32 = 0.0;
     let mut vv : Vec<u32> = vec![];
+    if models.is_empty() { return (0.0, 0, 0.0, 0, 0); }
 
     for model in models {
-        let ll = model.len();
+        let ll = model.points.len();
         vv.push(ll as u32);
         if (ll as u32) < min {
             min = ll as u32;
@@ -128,7 +780,7 @@ fn find_stats ( models : &Vec<Vec<Point>> ) -> (f32, u32, f32, u32, u32) {
         if (ll as u32) > max {
             max = ll as u32;
         }
-        mean = mean + model.len() as f32;
+        mean = mean + ll as f32;
     }
     
     vv.sort();
@@ -137,274 +789,3224 @@ fn find_stats ( models : &Vec<Vec<Point>> ) -> (f32, u32, f32, u32, u32) {
     let vlen = vv.len();
 
     for ll in vv {
-        sd = (ll as f32 - mean) * (ll as f32 - mean);
+        sd += (ll as f32 - mean) * (ll as f32 - mean);
     }
     sd = (sd / vlen as f32).sqrt();
     
     (mean, median, sd, min, max)
 }
 
-/// Returns a Vec of Point - the model
-/// Scale and move all the points so they are in WIDTH, HEIGHT
-/// and the Centre of mass moves to the origin.
-/// We pass in the global scale as we don't want to scale per image.
-/// We are moving the centre of mass to the centre of the image though
-/// so we have to put in translation to our final model
+/// Returns a tuple of three f32 - the centre of the model.
+/// The centre of the bounding box of the points.
 /// 
 /// # Arguments
 /// 
-/// * `models` - A Vec of Vectors of Point
-/// * `scale` - An f32 representing the scale for the points
+/// * `model` - A Vec of Point
 ///
-fn scale_shift_model( model : &Vec<Point>, scale : f32 ) -> Vec<Point> {
-    let mut scaled : Vec<Point> = vec![];
+fn find_centre( model : &Vec<Point> ) -> (f32, f32, f32) {
     let mut minx : f32 = 1e10;
     let mut miny : f32 = 1e10;
+    let mut minz : f32 = 1e10;
     let mut maxx : f32 = -1e10;
     let mut maxy : f32 = -1e10;
+    let mut maxz : f32 = -1e10;
 
     for point in model {
         if point.x < minx { minx = point.x; }
         if point.y < miny { miny = point.y; }
+        if point.z < minz { minz = point.z; }
         if point.x > maxx { maxx = point.x; }
         if point.y > maxy { maxy = point.y; }
+        if point.z > maxz { maxz = point.z; }
     }
 
-    let com = ((maxx + minx) / 2.0, (maxy + miny) / 2.0);
+    ((maxx + minx) / 2.0, (maxy + miny) / 2.0, (maxz + minz) / 2.0)
+}
+
+/// Returns a Vec of Point - the model
+/// Scale and move all the points so they are in the image width, height
+/// and the Centre of mass moves to the origin.
+/// We pass in the global scale as we don't want to scale per image.
+/// We are moving the centre of mass to the centre of the image though
+/// so we have to put in translation to our final model
+/// 
+/// # Arguments
+/// 
+/// * `models` - A Vec of Vectors of Point
+/// * `com` - A tuple of three f32 - the centre to move to the origin
+/// * `settings` - A RenderSettings - the scale and image size
+///
+fn scale_shift_model( model : &Vec<Point>, com : (f32, f32, f32), settings : &RenderSettings ) -> Vec<Point> {
+    let mut scaled : Vec<Point> = vec![];
     /*let diag =((maxx - minx) * (maxx - minx) + (maxy - miny) * (maxy - miny)).sqrt();
     // Make scalar a little smaller after selecting the smallest
     let scalar = (WIDTH as f32 / diag).min(HEIGHT as f32 / diag) * SHRINK;*/
-    let scalar = scale * (WIDTH as f32) * SHRINK;
+    let scalar = settings.scalar();
         
      for point in model {
         let np = Point {
             x : (point.x - com.0) * scalar,
-            y : (point.y - com.1) * scalar
+            y : (point.y - com.1) * scalar,
+            z : (point.z - com.2) * scalar,
+            sigma : point.sigma * scalar,
+            ..*point
         };
         scaled.push(np);
     } 
     scaled
 }
 
-/// Returns None
+/// Returns a HeaderValue that fitrs can write for a real number.
+/// fitrs divides a real down to one digit before the point and prints
+/// whatever is left, which never finishes for zero and can spill over
+/// the 20 columns of a card, so round it until it fits.
+/// # Arguments
+///
+/// * `value` - An f64 - the number to write
+///
+fn header_real(value : f64) -> HeaderValue {
+    if value == 0.0 { return HeaderValue::IntegerNumber(0); }
+    if !value.is_finite() { return HeaderValue::CharacterString(value.to_string()); }
+
+    for digits in (0..12).rev() {
+        let rounded : f64 = format!("{:.*e}", digits, value).parse().unwrap();
+        // The same steps as fitrs takes
+        let mut f = rounded;
+        let mut exp : i32 = 0;
+        while f.abs() >= 10.0 { f /= 10.0; exp += 1; }
+        while f.abs() < 1.0 { f *= 10.0; exp -= 1; }
+        if format!("{}E+{:02}", f, exp.abs()).len() <= 20 {
+            return HeaderValue::RealFloatingNumber(rounded);
+        }
+    }
+    HeaderValue::CharacterString(value.to_string())
+}
+
+/// Returns an f32 and three f32 - the angle of a rotation in degrees,
+/// from -180 to 180, and the unit axis it turns about. The axis is
+/// picked to point up in z (or y, or x, if it lies flat), so a spin
+/// in the image plane is always about +z and its angle keeps its sign.
+/// # Arguments
+///
+/// * `rotation` - A Rotation3 - the rotation
+///
+fn signed_rotation(rotation : &Rotation3<f32>) -> (f32, (f32, f32, f32)) {
+    match rotation.axis_angle() {
+        Some((axis, angle)) => {
+            let (x, y, z) = (axis.x, axis.y, axis.z);
+            let flip = z < 0.0 || (z == 0.0 && (y < 0.0 || (y == 0.0 && x < 0.0)));
+            let sign = if flip { -1.0 } else { 1.0 };
+            // Adding 0 turns -0 into 0, which readers match exactly
+            (sign * angle.to_degrees() + 0.0, (sign * x + 0.0, sign * y + 0.0, sign * z + 0.0))
+        },
+        None => (0.0, (0.0, 0.0, 1.0))
+    }
+}
+
+/// Returns a Result
 /// Save a fits image
 /// # Arguments
 /// 
 /// * `img` - A Vec of Vectors of f32 - the pixels
 /// * `filename` - A String - the filename to save
+/// * `settings` - A RenderSettings - recorded in the header
+/// * `info` - An ImageInfo - where the image came from, for the header
+///
+pub fn save_fits(img : &Vec<Vec<f32>>, filename : &String, settings : &RenderSettings, info : &ImageInfo) -> Result<(), Box<Error>> {
+    let mut primary_hdu = 
+        Hdu::new(&[settings.width as usize , settings.height as usize], fits_plane(img, settings));
+    // Insert values in header
+    run_keywords(&mut primary_hdu, settings);
+    image_keywords(&mut primary_hdu, info);
+    Fits::create(filename, primary_hdu)?;
+    Ok(())
+}
+
+/// Returns a Vec of f32 - an image in FITS order, rows of x running
+/// from the top of the image down.
+/// # Arguments
+///
+/// * `img` - A Vec of Vec of f32 - the image
+/// * `settings` - A RenderSettings - the image size
 ///
-pub fn save_fits(img : &Vec<Vec<f32>>, filename : &String) {
-    let mut data : Vec<f32> = (0..HEIGHT)
-        .map(|i| (0..WIDTH).map(
-               move |j| (i + j) as f32)).flatten().collect();
+fn fits_plane(img : &Vec<Vec<f32>>, settings : &RenderSettings) -> Vec<f32> {
+    let width = settings.width;
+    let height = settings.height;
+    let mut data : Vec<f32> = vec![0.0; (width * height) as usize];
 
-    for _y in 0..HEIGHT {
-        for _x in 0..WIDTH {
-            let idx : usize = (_y * WIDTH +_x ) as usize; 
-            data[idx] = img[_x as usize][(HEIGHT - _y - 1) as usize];
+    for _y in 0..height {
+        for _x in 0..width {
+            let idx : usize = (_y * width +_x ) as usize; 
+            data[idx] = img[_x as usize][(height - _y - 1) as usize];
             // / intensity * MULTFAC;
         }
     }
-
-    let mut primary_hdu = 
-        Hdu::new(&[WIDTH as usize , HEIGHT as usize], data);
-    // Insert values in header
-    primary_hdu.insert("NORMALISATION", "NONE");
-    primary_hdu.insert("WIDTH", WIDTH as i32);
-    primary_hdu.insert("HEIGHT", HEIGHT as i32);
-    Fits::create(filename, primary_hdu).expect("Failed to create");  
+    data
 }
 
-/// Returns a Vec of Point - a model
-/// Drop points so we are equal to or under a max.
+/// Returns None
+/// Write the keywords that are the same for every image of a run.
 /// # Arguments
-/// 
-/// * `img` - A Vec of Vectors of Point - a model
-/// * `max_points` - A usize representing the maximum number of points
 ///
-/*
-pub fn drop_points(img : &Vec<Point>, max_points : usize) -> Vec<Point> {
-    let mut fmodel : Vec<Point> = vec!();
-    let mut rng = rand::thread_rng();
-    let mut choices : Vec<usize> = vec!(); 
+/// * `hdu` - An Hdu - the header to write to
+/// * `settings` - A RenderSettings - recorded in the header
+///
+fn run_keywords(hdu : &mut Hdu, settings : &RenderSettings) {
+    let width = settings.width;
+    let height = settings.height;
+    // Older readers look for NORMALISATION, so it says the same as WNORM
+    hdu.insert("NORMALISATION", settings.weight_norm.to_string());
+    hdu.insert("WEIGHT", settings.weight_name.clone());
+    hdu.insert("WNORM", settings.weight_norm.to_string());
+    hdu.insert("WIDTH", width as i32);
+    hdu.insert("HEIGHT", height as i32);
+    // Too big for a FITS integer so this goes in as a string
+    hdu.insert("SEED", settings.seed.to_string());
+    hdu.insert("SRCFILE", settings.source.clone());
+    hdu.insert("NDRAWS", settings.draws as i32);
+    hdu.insert("MAXPTS", settings.max_points as i32);
+    hdu.insert("KEEPFRAC", header_real(settings.keep_fraction as f64));
+    hdu.insert("NAUGMENT", settings.augments as i32);
+    hdu.insert("ANGLES", match settings.angles {
+        AngleMode::Random => "RANDOM",
+        AngleMode::Even => "EVEN"
+    });
+    hdu.insert("RENDMODE", settings.mode.to_string());
+    hdu.insert("JITMODE", settings.jitter_mode.to_string());
 
-    for i in 0..max_points {
-        let mut ridx = rng.gen_range(0, img.len()-1);
-        while choices.contains(&ridx) {
-            ridx = rng.gen_range(0, img.len()-1);
-        }
+    // How the model was placed - pixel = R * (p - centre) * scalar + image centre,
+    // with the image flipped vertically when written out.
+    hdu.insert("OFFSETX", header_real((width as f64) / 2.0));
+    hdu.insert("OFFSETY", header_real((height as f64) / 2.0));
+    hdu.insert("SCALE", header_real(settings.scale as f64));
+    hdu.insert("SCALAR", header_real(settings.scalar() as f64));
+    hdu.insert("SIGMA", header_real(settings.sigma as f64));
+    hdu.insert("SIGMODE", match settings.sigma_mode {
+        SigmaMode::Global => "GLOBAL",
+        SigmaMode::Column => "COLUMN"
+    });
+}
+
+/// Returns None
+/// Write the keywords that tie one image to its model.
+/// # Arguments
+///
+/// * `hdu` - An Hdu - the header to write to
+/// * `info` - An ImageInfo - where the image came from
+///
+fn image_keywords(hdu : &mut Hdu, info : &ImageInfo) {
+    for (key, value) in image_cards(info) {
+        hdu.insert(key, value);
+    }
+}
 
-        choices.push(ridx);
-        fmodel.push(img[ridx])
+/// Returns a Vec of keyword and HeaderValue - the keywords that
+/// describe one image, in order, for `image_keywords` and for the
+/// extensions we write ourselves.
+/// # Arguments
+///
+/// * `info` - An ImageInfo - where the image came from
+///
+fn image_cards(info : &ImageInfo) -> Vec<(&'static str, HeaderValue)> {
+    let mut cards : Vec<(&'static str, HeaderValue)> = vec![];
+    cards.push(("MODSEED", HeaderValue::CharacterString(info.seed.to_string())));
+    cards.push(("MODELIDX", HeaderValue::IntegerNumber(info.index as i32)));
+    if info.id >= i32::min_value() as i64 && info.id <= i32::max_value() as i64 {
+        cards.push(("MODELID", HeaderValue::IntegerNumber(info.id as i32)));
+    } else {
+        cards.push(("MODELID", HeaderValue::CharacterString(info.id.to_string())));
     }
+    cards.push(("NPOINTS", HeaderValue::IntegerNumber(info.npoints as i32)));
+    cards.push(("DRAW", HeaderValue::IntegerNumber(info.draw as i32)));
+    cards.push(("AUGMENT", HeaderValue::IntegerNumber(info.augment as i32)));
+    cards.push(("JITTER", header_real(info.jitter as f64)));
 
-    fmodel
-}*/
+    let q = UnitQuaternion::from_rotation_matrix(&info.rotation);
+    let (angle, axis) = signed_rotation(&info.rotation);
+    cards.push(("ROTANGLE", header_real(angle as f64)));
+    cards.push(("ROTAXISX", header_real(axis.0 as f64)));
+    cards.push(("ROTAXISY", header_real(axis.1 as f64)));
+    cards.push(("ROTAXISZ", header_real(axis.2 as f64)));
+    cards.push(("QUATW", header_real(q.w as f64)));
+    cards.push(("QUATX", header_real(q.i as f64)));
+    cards.push(("QUATY", header_real(q.j as f64)));
+    cards.push(("QUATZ", header_real(q.k as f64)));
+    cards.push(("CENTREX", header_real(info.centre.0 as f64)));
+    cards.push(("CENTREY", header_real(info.centre.1 as f64)));
+    cards.push(("CENTREZ", header_real(info.centre.2 as f64)));
+    cards
+}
 
-/// Returns a Vec of Point - a model
-/// Drop points so we are equal to or under a max.
+/// Returns a Result - write a chunk of images into one FITS file,
+/// as a cube or as one extension each, followed by their table.
 /// # Arguments
-/// 
-/// * `models` - A Vec of Vectors of Point - a model
-/// * `out_path` - A String representing the path to render to
-/// * `nthreads` - A u32 - the number of threads to spin up
-/// * `pertubations` - A u32 - how many angles to use in the spin
-/// * `sigma` - An f32 - what sigma value to use
-/// * `scale` - An f32 - what scale to use
-/// * `max_points` - A usize - maximum number of points to 
 ///
-fn render (models : &Vec<Vec<Point>>, out_path : &String,  nthreads : u32, sigma : f32, scale : f32, max_points : usize) {
-    // Split into threads here I think
-    let pi = std::f32::consts::PI;
-    let (tx, rx) = channel();
-    let mut progress : i32 = 0;
-    let mut pool = Pool::new(nthreads);
-
-    let num_runs = models.len() as u32;
-    let truns = (num_runs / nthreads) as u32;
-    let spare = (num_runs % nthreads) as u32;
-    let mut pb = ProgressBar::new(num_runs as u64);
-    pb.format("╢▌▌░╟");
+/// * `planes` - A Vec of ManifestRow and image - the chunk, in order
+/// * `filename` - A String - the file to write
+/// * `settings` - A RenderSettings - the size and output format
+///
+fn save_chunk(planes : &Vec<(ManifestRow, Vec<Vec<f32>>)>, filename : &String, settings : &RenderSettings) -> Result<(), Box<Error>> {
+    let size = [settings.width as usize, settings.height as usize, planes.len()];
 
-    pool.scoped(|scoped| {
-        for _t in 0..nthreads {
-            let tx = tx.clone();
-            let start : usize = (_t * truns) as usize;
-            let mut end = ((_t + 1)  * truns) as usize;
-            if _t == nthreads - 1 { end = end + (spare as usize); }
-            let cslice = &models[start..end];
-           
-            scoped.execute( move || { 
-                let mut rng = thread_rng();
-                let side = Uniform::new(-pi, pi);
-
-                for _i in 0..cslice.len() {
-                    // Slightly inefficient if we are dropping points
-                    let mut scaled = scale_shift_model(&cslice[_i], scale);
-                    //if max_points != 0 {
-                    //    let fslice = drop_points(&cslice[_i], max_points);
-                    //    scaled = scale_shift_model(&fslice, scale);
-                    //}
-                    
-             
-                    let mut timg : Vec<Vec<f32>> = vec![];
-
-                    // Could be faster I bet
-                    for _x in 0..WIDTH {
-                        let mut tt : Vec<f32> = vec![];
-                        for _y in 0..HEIGHT { tt.push(0.0); }
-                        timg.push(tt);
-                    }
-                    // A random rotation around the plane
-                    let rr = rng.sample(side);
-                    let rm = (rr.cos(), -rr.sin(), rr.sin(), rr.cos());
-
-                    for ex in 0..WIDTH {
-                        for ey in 0..HEIGHT {
-                            for point in &scaled {
-                                let xs = point.x * rm.0 + point.y * rm.1;
-                                let ys = point.x * rm.2 + point.y * rm.3;
-                                let xf = xs + (WIDTH as f32/ 2.0);
-                                let yf = ys + (HEIGHT as f32 / 2.0);
-                                if xf >= 0.0 && xf < WIDTH as f32 && yf >= 0.0 && yf < HEIGHT as f32 {   
-                                    let pval = (1.0 / (2.0 * pi * sigma.powf(2.0))) *
-                                        (-((ex as f32 - xf).powf(2.0) + (ey as f32 - yf).powf(2.0)) / (2.0*sigma.powf(2.0))).exp();        
-                                    timg[ex as usize][ey as usize] += pval;
-                                }
-                                // We may get ones that exceed but it's very likely they are outliers
-                                /*else {
-                                    // TODO - ideally we send an error that propagates
-                                    // and kills all other threads and quits cleanly
-                                    println!("Point still exceeding range in image");
-                                }*/
-                            }
-                        }
-                    }
-                    
-                    let fidx = format!("/image_{:06}.fits",
-                        ((start + _i) as usize));
-                    let mut fitspath = out_path.clone();
-                    fitspath.push_str(&fidx);
-                    save_fits(&timg, &fitspath);
-                    tx.send(_i).unwrap();
-                }
-            });
+    if settings.format == OutputFormat::Npy || settings.format == OutputFormat::Npz {
+        let images : Vec<&Vec<Vec<f32>>> = planes.iter().map(|(_, img)| img).collect();
+        let stack = NpyArray::images(&images, size[0], size[1], settings.dtype);
+        if settings.format == OutputFormat::Npy {
+            return npy::save_npy(&stack, filename);
         }
+        let rows : Vec<&ManifestRow> = planes.iter().map(|(row, _)| row).collect();
+        let mut arrays = vec![(String::from("images"), stack)];
+        arrays.extend(manifest_arrays(&rows));
+        return npy::save_npz(&arrays, filename);
+    }
 
-        // Update our progress bar
-        while progress < num_runs as i32 {
-            match rx.try_recv() {
-                Ok(_a) => {
-                    pb.inc();
-                    progress = progress + 1;
-                }, Err(_e) => {}
-            }
+    if settings.format == OutputFormat::Cube {
+        let mut data : Vec<f32> = Vec::with_capacity(size[0] * size[1] * size[2]);
+        for (_, img) in planes { data.extend(fits_plane(img, settings)); }
+        let mut primary_hdu = Hdu::new(&size, data);
+        primary_hdu.insert("EXTEND", HeaderValue::Logical(true));
+        run_keywords(&mut primary_hdu, settings);
+        Fits::create(filename, primary_hdu)?;
+    } else {
+        let mut primary_hdu = Hdu::empty();
+        primary_hdu.insert("EXTEND", HeaderValue::Logical(true));
+        run_keywords(&mut primary_hdu, settings);
+        Fits::create(filename, primary_hdu)?;
+        // fitrs puts PCOUNT and GCOUNT last, so we write the extensions
+        // ourselves to give each one the keywords of its image
+        let mut file = fs::OpenOptions::new().append(true).open(filename)?;
+        for (row, img) in planes {
+            file.write_all(&image_extension(img, settings, &row.info))?;
         }
-    });
+    }
+
+    let rows : Vec<&ManifestRow> = planes.iter().map(|(row, _)| row).collect();
+    append_table(&rows, filename)
 }
 
-/// Returns a Result of Vec of Vec of Point.
-/// Parse the CSV file
+/// Returns a Vec of name and NpyArray - one array per manifest column,
+/// for an npz. Missing whole numbers become -1 and missing reals NaN.
+/// Columns with nothing in them are left out.
 /// # Arguments
-/// 
-/// * `path` - A String - the path to the CSV file
 ///
-fn parse_csv(path : &String) -> Result<Vec<Vec<Point>>, Box<Error>> {
-    let mut models : Vec<Vec<Point>>  = vec!();
-    let file = File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut model : Vec<Point> = vec![];
-
-    for result in rdr.records() {
-        let record = result?;
-        //println!("{:?}", record);
-        let x: f32 = record[0].parse()?;
-        let y: f32 = record[1].parse()?;
+/// * `rows` - A Vec of ManifestRow - the rows, one per plane
+///
+fn manifest_arrays(rows : &Vec<&ManifestRow>) -> Vec<(String, NpyArray)> {
+    let fields : Vec<Vec<(&'static str, ManifestValue)>> = rows.iter().map(|r| r.fields()).collect();
+    let mut arrays : Vec<(String, NpyArray)> = vec![];
+    if fields.len() == 0 { return arrays; }
 
-        let p = Point {
-            x : x,
-            y : y
+    for c in 0..fields[0].len() {
+        let name = String::from(fields[0][c].0);
+        let column : Vec<&ManifestValue> = fields.iter().map(|f| &f[c].1).collect();
+        let array = match column.iter().find(|v| match v { ManifestValue::Missing => false, _ => true }) {
+            Some(ManifestValue::Int(_)) => NpyArray::ints(&column.iter().map(|v| match v {
+                ManifestValue::Int(i) => *i,
+                _ => -1
+            }).collect::<Vec<_>>()),
+            Some(ManifestValue::Real(_)) => NpyArray::reals(&column.iter().map(|v| match v {
+                ManifestValue::Real(r) => *r,
+                _ => std::f32::NAN
+            }).collect::<Vec<_>>()),
+            Some(_) => NpyArray::texts(&column.iter().map(|v| match v {
+                ManifestValue::Missing => String::new(),
+                v => v.to_string()
+            }).collect::<Vec<_>>()),
+            None => continue
         };
-        model.push(p);
+        arrays.push((name, array));
     }
-    models.push(model);
-    Ok(models)
+    arrays
 }
 
-fn main() {
-     let args: Vec<_> = env::args().collect();
-    
-    if args.len() < 5 {
-        println!("Usage: render <path to csv file> <path to output> <threads> <sigma>"); 
-        process::exit(1);
-    }
-    
-    let nthreads = args[3].parse::<u32>().unwrap();
-    let sigma = args[4].parse::<f32>().unwrap();
-    let mut accepted : Vec<usize> = vec!();
+/// Returns a String - one 80 character FITS header card.
+/// # Arguments
+///
+/// * `key` - A str - the keyword, at most 8 characters
+/// * `value` - A str - the value, already formatted
+/// * `text` - A bool - quote the value as a string
+///
+fn fits_card(key : &str, value : &str, text : bool) -> String {
+    let card = if text {
+        format!("{:<8}= '{:<8}'", key, value.replace("'", "''"))
+    } else {
+        format!("{:<8}= {:>20}", key, value)
+    };
+    format!("{:<80.80}", card)
+}
+
+/// Returns a String - one 80 character FITS header card for a value
+/// fitrs would write.
+/// # Arguments
+///
+/// * `key` - A str - the keyword, at most 8 characters
+/// * `value` - A HeaderValue - the value
+///
+fn header_card(key : &str, value : &HeaderValue) -> String {
+    match value {
+        HeaderValue::CharacterString(text) => fits_card(key, text, true),
+        HeaderValue::Logical(b) => fits_card(key, if *b { "T" } else { "F" }, false),
+        HeaderValue::IntegerNumber(n) => fits_card(key, &n.to_string(), false),
+        HeaderValue::RealFloatingNumber(r) => fits_card(key, &fits_real(*r), false),
+        HeaderValue::ComplexIntegerNumber(a, b) => fits_card(key, &format!("({}, {})", a, b), false),
+        HeaderValue::ComplexFloatingNumber(a, b) => fits_card(key, &format!("({}, {})", fits_real(*a), fits_real(*b)), false)
+    }
+}
+
+/// Returns a String - a real number the way FITS headers write it,
+/// with a point in the mantissa and a signed two digit exponent.
+/// # Arguments
+///
+/// * `value` - An f64 - the number
+///
+fn fits_real(value : f64) -> String {
+    let text = format!("{:E}", value);
+    let mut parts = text.split('E');
+    let mantissa = parts.next().unwrap_or("0");
+    let exp : i32 = parts.next().and_then(|e| e.parse().ok()).unwrap_or(0);
+    let point = if mantissa.contains('.') { "" } else { ".0" };
+    format!("{}{}E{}{:02}", mantissa, point, if exp < 0 { '-' } else { '+' }, exp.abs())
+}
+
+/// Returns a Vec of u8 - one image as a FITS image extension, with
+/// the keywords of the image, ready to append to a file.
+/// # Arguments
+///
+/// * `img` - A Vec of Vec of f32 - the image
+/// * `settings` - A RenderSettings - the image size
+/// * `info` - An ImageInfo - where the image came from
+///
+fn image_extension(img : &Vec<Vec<f32>>, settings : &RenderSettings, info : &ImageInfo) -> Vec<u8> {
+    let mut header : Vec<String> = vec![
+        fits_card("XTENSION", "IMAGE", true),
+        fits_card("BITPIX", "-32", false),
+        fits_card("NAXIS", "2", false),
+        fits_card("NAXIS1", &settings.width.to_string(), false),
+        fits_card("NAXIS2", &settings.height.to_string(), false),
+        fits_card("PCOUNT", "0", false),
+        fits_card("GCOUNT", "1", false)
+    ];
+    for (key, value) in image_cards(info) {
+        header.push(header_card(key, &value));
+    }
+    header.push(format!("{:<80}", "END"));
+
+    let mut bytes : Vec<u8> = header.concat().into_bytes();
+    while bytes.len() % 2880 != 0 { bytes.push(b' '); }
+    let data_start = bytes.len();
+    for v in fits_plane(img, settings) {
+        bytes.extend_from_slice(&v.to_be_bytes());
+    }
+    while (bytes.len() - data_start) % 2880 != 0 { bytes.push(0); }
+    bytes
+}
+
+/// Returns a Result - append the manifest rows of a packed file as
+/// a binary table extension. fitrs cannot write tables, so we do.
+/// Integers become 64 bit columns, reals 32 bit and text fixed width.
+/// # Arguments
+///
+/// * `rows` - A Vec of ManifestRow - one per plane
+/// * `filename` - A String - the FITS file to append to
+///
+fn append_table(rows : &Vec<&ManifestRow>, filename : &String) -> Result<(), Box<Error>> {
+    let fields : Vec<Vec<(&'static str, ManifestValue)>> = rows.iter().map(|r| r.fields()).collect();
+    if fields.len() == 0 { return Ok(()); }
+    let ncols = fields[0].len();
+
+    // The first value that is not missing decides each column's type
+    let mut forms : Vec<String> = vec![];
+    let mut widths : Vec<usize> = vec![];
+    for c in 0..ncols {
+        let first = fields.iter().map(|f| &f[c].1).find(|v| match v {
+            ManifestValue::Missing => false,
+            _ => true
+        });
+        match first {
+            Some(ManifestValue::Int(_)) => { forms.push(String::from("K")); widths.push(8); },
+            Some(ManifestValue::Real(_)) => { forms.push(String::from("E")); widths.push(4); },
+            _ => {
+                let w = fields.iter().map(|f| f[c].1.to_string().len()).max().unwrap_or(0).max(1);
+                forms.push(format!("{}A", w));
+                widths.push(w);
+            }
+        }
+    }
+    let row_bytes : usize = widths.iter().sum();
+
+    let mut header : Vec<String> = vec![
+        fits_card("XTENSION", "BINTABLE", true),
+        fits_card("BITPIX", "8", false),
+        fits_card("NAXIS", "2", false),
+        fits_card("NAXIS1", &row_bytes.to_string(), false),
+        fits_card("NAXIS2", &fields.len().to_string(), false),
+        fits_card("PCOUNT", "0", false),
+        fits_card("GCOUNT", "1", false),
+        fits_card("TFIELDS", &ncols.to_string(), false)
+    ];
+    for c in 0..ncols {
+        header.push(fits_card(&format!("TTYPE{}", c + 1), fields[0][c].0, true));
+        header.push(fits_card(&format!("TFORM{}", c + 1), &forms[c], true));
+    }
+    header.push(fits_card("EXTNAME", "PLANES", true));
+    header.push(format!("{:<80}", "END"));
+
+    let mut bytes : Vec<u8> = header.concat().into_bytes();
+    while bytes.len() % 2880 != 0 { bytes.push(b' '); }
+    let data_start = bytes.len();
+
+    for f in &fields {
+        for c in 0..ncols {
+            match (&f[c].1, forms[c].as_str()) {
+                (ManifestValue::Int(n), "K") => bytes.extend_from_slice(&n.to_be_bytes()),
+                (_, "K") => bytes.extend_from_slice(&0i64.to_be_bytes()),
+                (ManifestValue::Real(r), "E") => bytes.extend_from_slice(&r.to_be_bytes()),
+                (_, "E") => bytes.extend_from_slice(&std::f32::NAN.to_be_bytes()),
+                (v, _) => {
+                    let text = format!("{:<w$.w$}", v.to_string(), w = widths[c]);
+                    bytes.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+    }
+    while (bytes.len() - data_start) % 2880 != 0 { bytes.push(0); }
+
+    let mut file = fs::OpenOptions::new().append(true).open(filename)?;
+    file.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Returns a Vec of Point - a model
+/// Drop points so we are equal to or under a max. The points kept
+/// are drawn without replacement, every point equally likely, and
+/// stay in their original order.
+/// # Arguments
+/// 
+/// * `rng` - A Rng - the random number generator
+/// * `img` - A Vec of Point - a model
+/// * `max_points` - A usize representing the maximum number of points
+///
+pub fn drop_points<R: Rng>(rng : &mut R, img : &Vec<Point>, max_points : usize) -> Vec<Point> {
+    if max_points >= img.len() { return img.clone(); }
+    let mut choices = rand::seq::index::sample(rng, img.len(), max_points).into_vec();
+    choices.sort();
+    choices.iter().map(|i| img[*i]).collect()
+}
+
+/// Returns an f32 - the sigma in pixels of the Gaussian for a point.
+/// In column mode this is the point's precision (already scaled to
+/// pixels), multiplied and clamped as asked. Points without a usable
+/// precision fall back to the global sigma.
+/// # Arguments
+/// 
+/// * `point` - A Point - a scaled point
+/// * `settings` - A RenderSettings - the sigma settings
+///
+fn point_sigma(point : &Point, settings : &RenderSettings) -> f32 {
+    if settings.sigma_mode == SigmaMode::Global || !(point.sigma > 0.0) {
+        return settings.sigma;
+    }
+    let mut sigma = point.sigma * settings.sigma_mult;
+    if let Some(smin) = settings.sigma_min { sigma = sigma.max(smin); }
+    if let Some(smax) = settings.sigma_max { sigma = sigma.min(smax); }
+    sigma
+}
+
+/// Returns a Vec of Point and an f32 - the points, each moved by
+/// Gaussian noise on every axis, and the mean sigma of the noise in
+/// CSV units. The points are already scaled to pixels.
+/// # Arguments
+/// 
+/// * `rng` - A Rng - the random number generator
+/// * `points` - A Vec of Point - the scaled model
+/// * `settings` - A RenderSettings - the jitter settings
+///
+fn jitter_points<R: Rng>(rng : &mut R, points : &Vec<Point>, settings : &RenderSettings) -> (Vec<Point>, f32) {
+    if settings.jitter_mode == JitterMode::None || points.len() == 0 {
+        return (points.clone(), 0.0);
+    }
+    let scalar = settings.scalar();
+    let mut total : f32 = 0.0;
+
+    let jittered = points.iter().map(|point| {
+        let sd = match settings.jitter_mode {
+            JitterMode::Precision if point.sigma > 0.0 => point.sigma / scalar,
+            _ => settings.jitter
+        };
+        total += sd;
+        if !(sd > 0.0) { return *point; }
+        let normal = Normal::new(0.0, sd * scalar).unwrap();
+        Point {
+            x : point.x + normal.sample(rng),
+            y : point.y + normal.sample(rng),
+            z : point.z + normal.sample(rng),
+            ..*point
+        }
+    }).collect();
+
+    (jittered, total / points.len() as f32)
+}
+
+/// Returns None
+/// Normalise the point weights of a model in place.
+/// # Arguments
+/// 
+/// * `points` - A Vec of Point - the model
+/// * `norm` - A WeightNorm - how to normalise
+///
+fn normalise_weights(points : &mut Vec<Point>, norm : WeightNorm) {
+    if points.len() == 0 { return; }
+    let total : f32 = points.iter().map(|p| p.weight).sum();
+    let largest : f32 = points.iter().fold(0.0, |m, p| m.max(p.weight));
+    let divisor = match norm {
+        WeightNorm::None => 1.0,
+        WeightNorm::Sum => total,
+        WeightNorm::Mean => total / points.len() as f32,
+        WeightNorm::Max => largest
+    };
+    if divisor > 0.0 {
+        for point in points.iter_mut() { point.weight = point.weight / divisor; }
+    }
+}
+
+/// Returns a Rotation3 - a random rotation for one image.
+/// Plane spins around the z axis. Tilt first tilts the model about
+/// a random axis in the image plane, picking the tilt so the view
+/// direction is uniform over a cap of the sphere up to `max_tilt`,
+/// then spins it. Sphere is a uniform random quaternion (Shoemake).
+/// # Arguments
+/// 
+/// * `rng` - A Rng - the random number generator
+/// * `mode` - A RotationMode - which rotations to draw from
+/// * `max_tilt` - An f32 - the largest tilt in radians, for Tilt
+///
+fn random_rotation<R: Rng>(rng : &mut R, mode : RotationMode, max_tilt : f32) -> Rotation3<f32> {
+    let pi = std::f32::consts::PI;
+    let spin = Rotation3::from_axis_angle(&Vector3::z_axis(), rng.sample(Uniform::new(-pi, pi)));
+
+    match mode {
+        RotationMode::Plane => spin,
+        RotationMode::Tilt => {
+            let phi : f32 = rng.sample(Uniform::new(-pi, pi));
+            let axis = Unit::new_normalize(Vector3::new(phi.cos(), phi.sin(), 0.0));
+            let ct : f32 = rng.sample(Uniform::new_inclusive(max_tilt.cos(), 1.0));
+            spin * Rotation3::from_axis_angle(&axis, ct.acos())
+        },
+        RotationMode::Sphere => {
+            let u1 : f32 = rng.gen();
+            let u2 : f32 = rng.gen();
+            let u3 : f32 = rng.gen();
+            let q = Quaternion::new(
+                u1.sqrt() * (2.0 * pi * u3).cos(),
+                (1.0 - u1).sqrt() * (2.0 * pi * u2).sin(),
+                (1.0 - u1).sqrt() * (2.0 * pi * u2).cos(),
+                u1.sqrt() * (2.0 * pi * u3).sin());
+            UnitQuaternion::from_quaternion(q).to_rotation_matrix()
+        }
+    }
+}
+
+/// Returns a Vec of Rotation3 - one rotation for each augmented
+/// render of a model.
+/// # Arguments
+/// 
+/// * `rng` - A Rng - the model's random number generator
+/// * `settings` - A RenderSettings - the rotation mode and augments
+///
+fn augment_rotations<R: Rng>(rng : &mut R, settings : &RenderSettings) -> Vec<Rotation3<f32>> {
+    let n = settings.augments.max(1);
+    match settings.angles {
+        AngleMode::Random => (0..n).map(|_| {
+            random_rotation(rng, settings.rotation, settings.max_tilt)
+        }).collect(),
+        AngleMode::Even => {
+            let base = random_rotation(rng, settings.rotation, settings.max_tilt);
+            let step = 2.0 * std::f32::consts::PI / n as f32;
+            (0..n).map(|a| {
+                Rotation3::from_axis_angle(&Vector3::z_axis(), step * a as f32) * base
+            }).collect()
+        }
+    }
+}
+
+/// Returns a String - the file name of an image.
+/// With more than one render per model, the render number follows
+/// the model's.
+/// # Arguments
+/// 
+/// * `position` - A usize - where the model is in the rendered list
+/// * `render` - A u32 - which of the model's renders this is
+/// * `renders` - A u32 - how many renders each model gets
+///
+fn image_name(position : usize, render : u32, renders : u32) -> String {
+    if renders > 1 {
+        format!("image_{:06}_{:03}.fits", position, render)
+    } else {
+        format!("image_{:06}.fits", position)
+    }
+}
+
+/// Returns a String - the file name of a chunk of packed images.
+/// # Arguments
+/// 
+/// * `chunk` - A usize - which chunk
+/// * `format` - An OutputFormat - which gives the extension
+///
+fn chunk_name(chunk : usize, format : OutputFormat) -> String {
+    let ext = match format {
+        OutputFormat::Npy => "npy",
+        OutputFormat::Npz => "npz",
+        _ => "fits"
+    };
+    format!("images_{:04}.{}", chunk, ext)
+}
+
+/// The sum, max, mean, standard deviation and non-zero pixel count
+/// of an image, as image_stats gives them.
+type ImageStats = (f32, f32, f32, f32, usize);
+
+/// Returns a String - where a file is written before it is renamed
+/// into place, so a file under its real name is always complete.
+/// # Arguments
+/// 
+/// * `path` - A String - the file's real name
+///
+fn temp_path(path : &String) -> String {
+    format!("{}.tmp", path)
+}
+
+/// Returns a Result - write a file under its temporary name and
+/// rename it into place, or remove what was written if that fails.
+/// # Arguments
+///
+/// * `path` - A String - the file's real name
+/// * `write` - A function that writes the file to the name it is given
+///
+fn write_via_temp<F>(path : &String, write : F) -> Result<(), Box<Error>>
+    where F : FnOnce(&String) -> Result<(), Box<Error>> {
+    let tmppath = temp_path(path);
+    let result = write(&tmppath).and_then(|_| fs::rename(&tmppath, path).map_err(From::from));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmppath);
+    }
+    result
+}
+
+/// Returns a bool - whether a finished output looks whole. Outputs
+/// are renamed into place once written, so this only guards against
+/// files that were cut short or are not ours.
+/// # Arguments
+/// 
+/// * `path` - A Path - the output
+///
+fn output_valid(path : &Path) -> bool {
+    let bytes = match fs::read(path) {
+        Ok(b) => b,
+        Err(_) => return false
+    };
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("fits") => bytes.len() > 0 && bytes.len() % 2880 == 0 && bytes.starts_with(b"SIMPLE  ="),
+        Some("npy") => bytes.starts_with(b"\x93NUMPY"),
+        // A zip ends with its directory, which has no comment here
+        Some("npz") => bytes.starts_with(b"PK\x03\x04") && bytes.len() >= 22 &&
+            bytes[bytes.len() - 22..].starts_with(b"PK\x05\x06"),
+        _ => bytes.len() > 0
+    }
+}
+
+/// Returns a String - everything that changes what a run writes,
+/// the split of each model included, so a resumed run can tell
+/// whether the outputs it finds are its own.
+/// # Arguments
+/// 
+/// * `models` - A Vec of Model - the models to render
+/// * `settings` - A RenderSettings - the render settings
+///
+fn run_fingerprint(models : &Vec<Model>, settings : &RenderSettings) -> String {
+    // FNV-1a over the models, so a change in the points shows up
+    let mut hash : u64 = 0xcbf2_9ce4_8422_2325;
+    let mut mix = |v : u64| {
+        for b in v.to_le_bytes().iter() {
+            hash = (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    for model in models {
+        mix(model.index as u64);
+        mix(model.id as u64);
+        mix(model.points.len() as u64);
+        // The split goes in the manifest, so moving a model to another
+        // split changes the output even if nothing else does
+        let split = model.split.as_ref().map_or(&[][..], |s| s.as_bytes());
+        mix(split.len() as u64);
+        for b in split { mix(*b as u64); }
+        for p in &model.points {
+            mix(((p.x.to_bits() as u64) << 32) | p.y.to_bits() as u64);
+            mix(((p.z.to_bits() as u64) << 32) | p.sigma.to_bits() as u64);
+            mix(p.weight.to_bits() as u64);
+        }
+    }
+
+    format!("seed={} models={} hash={:016x} size={}x{} scale={} shrink={} sigma={} sigmode={} mult={} \
+        sigmin={:?} sigmax={:?} weight={} wnorm={} maxpts={} keep={} draws={} rotation={} tilt={} \
+        augments={} angles={} jitter={}:{} mode={} splat={} format={} chunk={} dtype={} export={:?}:{}:{}:{:?}:{:?}",
+        settings.seed, models.len(), hash, settings.width, settings.height, settings.scale, settings.shrink,
+        settings.sigma, settings.sigma_mode as u8, settings.sigma_mult, settings.sigma_min, settings.sigma_max,
+        settings.weight_name, settings.weight_norm, settings.max_points, settings.keep_fraction,
+        settings.draws, settings.rotation as u8, settings.max_tilt, settings.augments, settings.angles as u8,
+        settings.jitter_mode, settings.jitter, settings.mode, settings.splat_error, settings.format as u8,
+        settings.chunk_size, settings.dtype as u8, settings.export.format.map(|f| f as u8),
+        settings.export.bits, settings.export.scaling as u8, settings.export.low, settings.export.high)
+}
+
+/// Returns a Result of a HashMap - the stats of every image the
+/// journal of an earlier run lists as written, by file and plane.
+/// # Arguments
+/// 
+/// * `path` - A Path - the journal
+/// * `fingerprint` - A str - what this run writes, to check against
+///
+fn read_journal(path : &Path, fingerprint : &str) -> Result<HashMap<(String, Option<usize>), ImageStats>, Box<Error>> {
+    let text = fs::read_to_string(path)?;
+    let mut done : HashMap<(String, Option<usize>), ImageStats> = HashMap::new();
+    let mut lines : Vec<&str> = text.lines().collect();
+    // A crash can cut the last line short, and a number cut short still
+    // parses, so a line is only complete once its newline is written
+    if !text.ends_with('\n') { lines.pop(); }
+
+    for (lidx, line) in lines.into_iter().enumerate() {
+        if lidx == 0 {
+            if line.trim_start_matches("# ") != fingerprint {
+                return Err(From::from(format!("{} was written by a run with other settings or models", path.display())));
+            }
+            continue;
+        }
+        if lidx == 1 { continue; }
+
+        let fields : Vec<&str> = line.split(',').collect();
+        let parsed = || -> Result<((String, Option<usize>), ImageStats), Box<Error>> {
+            if fields.len() != 7 { return Err(From::from("wrong number of fields")); }
+            let plane = if fields[1].len() == 0 { None } else { Some(fields[1].parse::<usize>()?) };
+            Ok(((fields[0].to_string(), plane), (fields[2].parse()?, fields[3].parse()?,
+                fields[4].parse()?, fields[5].parse()?, fields[6].parse()?)))
+        };
+        if let Ok((key, stats)) = parsed() {
+            done.insert(key, stats);
+        }
+    }
+    Ok(done)
+}
+
+/// Returns a String - the journal line of a written image, with its
+/// newline, so it can go to the file in one write.
+/// # Arguments
+/// 
+/// * `row` - A ManifestRow - the image
+///
+fn journal_line(row : &ManifestRow) -> String {
+    let plane = row.plane.map(|p| p.to_string()).unwrap_or(String::new());
+    format!("{},{},{},{},{},{},{}\n", row.file, plane, row.total, row.peak, row.mean, row.sd, row.nonzero)
+}
+
+/// Returns a HashMap of usize to ImageStats - the images, by their
+/// place in the run, that an earlier run finished. An image counts
+/// if the journal lists it and its file and export are whole. A
+/// packed file only counts if every image in it does.
+/// # Arguments
+/// 
+/// * `num_images` - A usize - the number of images in the run
+/// * `out_path` - A String - the output directory
+/// * `settings` - A RenderSettings - the output format
+/// * `journal` - A HashMap - the journal of the earlier run
+///
+fn completed_images(num_images : usize, out_path : &String, settings : &RenderSettings,
+    journal : &HashMap<(String, Option<usize>), ImageStats>) -> HashMap<usize, ImageStats> {
+    let renders = settings.renders() as usize;
+    let mut done : HashMap<usize, ImageStats> = HashMap::new();
+    let image_done = |ordinal : usize| -> bool {
+        settings.export.format.is_none() || {
+            let name = image_name(ordinal / renders, (ordinal % renders) as u32, renders as u32);
+            output_valid(&Path::new(out_path).join(name).with_extension(settings.export.extension()))
+        }
+    };
+
+    if settings.format == OutputFormat::Files {
+        for ordinal in 0..num_images {
+            let name = image_name(ordinal / renders, (ordinal % renders) as u32, renders as u32);
+            if let Some(stats) = journal.get(&(name.clone(), None)) {
+                if output_valid(&Path::new(out_path).join(&name)) && image_done(ordinal) {
+                    done.insert(ordinal, *stats);
+                }
+            }
+        }
+        return done;
+    }
+
+    let nchunks = (num_images + settings.chunk_size - 1) / settings.chunk_size;
+    for chunk in 0..nchunks {
+        let name = chunk_name(chunk, settings.format);
+        if !output_valid(&Path::new(out_path).join(&name)) { continue; }
+        let ordinals = (chunk * settings.chunk_size)..num_images.min((chunk + 1) * settings.chunk_size);
+        let stats : Vec<Option<&ImageStats>> = ordinals.clone()
+            .map(|o| journal.get(&(name.clone(), Some(o % settings.chunk_size)))).collect();
+        if stats.iter().all(|s| s.is_some()) && ordinals.clone().all(|o| image_done(o)) {
+            for (o, s) in ordinals.zip(stats) { done.insert(o, *s.unwrap()); }
+        }
+    }
+    done
+}
+
+/// Returns an f32 - how far from a point, in sigmas, we need to draw
+/// its Gaussian so that at most `splat_error` of its mass is left out.
+/// The mass of a 2D Gaussian beyond radius r is exp(-r^2 / 2 sigma^2).
+/// An error of zero or less means we draw across the whole image.
+/// # Arguments
+/// 
+/// * `splat_error` - An f32 - the fraction of mass we may leave out
+///
+fn splat_radius(splat_error : f32) -> f32 {
+    if splat_error <= 0.0 || splat_error >= 1.0 { return std::f32::INFINITY; }
+    (-2.0 * splat_error.ln()).sqrt()
+}
+
+/// Returns a Vec of Vectors of f32 - the image.
+/// Draw each point as a Gaussian, only touching the pixels within
+/// the truncation radius of it. Points are projected points in
+/// pixels from the image centre, with their sigma and weight.
+/// The Gaussians are summed, or with `peak` each pixel keeps the
+/// largest of them.
+/// # Arguments
+/// 
+/// * `projected` - A Vec of (x, y, sigma, weight)
+/// * `width` - A u32 - the image width
+/// * `height` - A u32 - the image height
+/// * `splat_error` - An f32 - the fraction of mass we may leave out
+/// * `peak` - A bool - take the maximum rather than the sum
+///
+fn splat_points(projected : &Vec<(f32, f32, f32, f32)>, width : u32, height : u32, splat_error : f32, peak : bool) -> Vec<Vec<f32>> {
+    let pi = std::f32::consts::PI;
+    let reach = splat_radius(splat_error);
+    let mut timg : Vec<Vec<f32>> = vec![vec![0.0; height as usize]; width as usize];
+
+    for &(xs, ys, sigma, weight) in projected {
+        let xf = xs + (width as f32/ 2.0);
+        let yf = ys + (height as f32 / 2.0);
+        // We may get ones that exceed but it's very likely they are outliers
+        if !(xf >= 0.0 && xf < width as f32 && yf >= 0.0 && yf < height as f32) { continue; }
+
+        let r = reach * sigma;
+        let sx = (xf - r).ceil().max(0.0) as u32;
+        let sy = (yf - r).ceil().max(0.0) as u32;
+        let ex_end = (xf + r).floor().min((width - 1) as f32) as u32;
+        let ey_end = (yf + r).floor().min((height - 1) as f32) as u32;
+
+        for ex in sx..(ex_end + 1) {
+            for ey in sy..(ey_end + 1) {
+                let pval = weight * (1.0 / (2.0 * pi * sigma.powf(2.0))) *
+                    (-((ex as f32 - xf).powf(2.0) + (ey as f32 - yf).powf(2.0)) / (2.0*sigma.powf(2.0))).exp();
+                if peak {
+                    timg[ex as usize][ey as usize] = timg[ex as usize][ey as usize].max(pval);
+                } else {
+                    timg[ex as usize][ey as usize] += pval;
+                }
+            }
+        }
+    }
+    timg
+}
+
+/// Returns a usize - how many points land outside the image, which
+/// splat_points and bin_points leave out.
+/// # Arguments
+///
+/// * `projected` - A Vec of (x, y, sigma, weight)
+/// * `width` - A u32 - the image width
+/// * `height` - A u32 - the image height
+///
+fn outside_image(projected : &Vec<(f32, f32, f32, f32)>, width : u32, height : u32) -> usize {
+    projected.iter().filter(|&&(xs, ys, _, _)| {
+        let xf = xs + (width as f32/ 2.0);
+        let yf = ys + (height as f32 / 2.0);
+        !(xf >= 0.0 && xf < width as f32 && yf >= 0.0 && yf < height as f32)
+    }).count()
+}
+
+/// Returns a Vec of Vectors of f32 - the image.
+/// Add the weight of each point to the pixel it falls in. Pixel
+/// centres are on whole numbers, as they are for the Gaussians.
+/// # Arguments
+/// 
+/// * `projected` - A Vec of (x, y, sigma, weight)
+/// * `width` - A u32 - the image width
+/// * `height` - A u32 - the image height
+///
+fn bin_points(projected : &Vec<(f32, f32, f32, f32)>, width : u32, height : u32) -> Vec<Vec<f32>> {
+    let mut timg : Vec<Vec<f32>> = vec![vec![0.0; height as usize]; width as usize];
+
+    for &(xs, ys, _sigma, weight) in projected {
+        let xf = xs + (width as f32/ 2.0);
+        let yf = ys + (height as f32 / 2.0);
+        if !(xf >= 0.0 && xf < width as f32 && yf >= 0.0 && yf < height as f32) { continue; }
+        let ex = ((xf + 0.5).floor() as usize).min(width as usize - 1);
+        let ey = ((yf + 0.5).floor() as usize).min(height as usize - 1);
+        timg[ex][ey] += weight;
+    }
+    timg
+}
+
+/// Returns a Vec of Vectors of f32 - the blurred image.
+/// A separable Gaussian blur, with the kernel cut at the same
+/// radius as the splatting and normalised so it keeps the mass.
+/// # Arguments
+/// 
+/// * `img` - A Vec of Vectors of f32 - the image
+/// * `sigma` - An f32 - the sigma of the blur in pixels
+/// * `splat_error` - An f32 - the fraction of mass we may leave out
+///
+fn blur_image(img : &Vec<Vec<f32>>, sigma : f32, splat_error : f32) -> Vec<Vec<f32>> {
+    let width = img.len();
+    if width == 0 || !(sigma > 0.0) { return img.clone(); }
+    let height = img[0].len();
+    let reach = (splat_radius(splat_error) * sigma).min(width.max(height) as f32);
+    let r = reach.floor() as i64;
+
+    let mut kernel : Vec<f32> = (-r..(r + 1)).map(|d| {
+        (-(d * d) as f32 / (2.0 * sigma * sigma)).exp()
+    }).collect();
+    let total : f32 = kernel.iter().sum();
+    for k in kernel.iter_mut() { *k = *k / total; }
+
+    let mut across : Vec<Vec<f32>> = vec![vec![0.0; height]; width];
+    for x in 0..width {
+        for (i, k) in kernel.iter().enumerate() {
+            let sx = x as i64 + i as i64 - r;
+            if sx < 0 || sx >= width as i64 { continue; }
+            for y in 0..height {
+                across[x][y] += k * img[sx as usize][y];
+            }
+        }
+    }
+
+    let mut timg : Vec<Vec<f32>> = vec![vec![0.0; height]; width];
+    for x in 0..width {
+        for y in 0..height {
+            let mut v = 0.0;
+            for (i, k) in kernel.iter().enumerate() {
+                let sy = y as i64 + i as i64 - r;
+                if sy < 0 || sy >= height as i64 { continue; }
+                v += k * across[x][sy as usize];
+            }
+            timg[x][y] = v;
+        }
+    }
+    timg
+}
+
+/// Returns a Vec of Vectors of f32 - the image, drawn in the
+/// render mode of the settings.
+/// # Arguments
+/// 
+/// * `projected` - A Vec of (x, y, sigma, weight)
+/// * `settings` - A RenderSettings - the mode, size and sigma
+///
+fn draw_points(projected : &Vec<(f32, f32, f32, f32)>, settings : &RenderSettings) -> Vec<Vec<f32>> {
+    match settings.mode {
+        RenderMode::Gauss => splat_points(projected, settings.width, settings.height, settings.splat_error, false),
+        RenderMode::Max => splat_points(projected, settings.width, settings.height, settings.splat_error, true),
+        RenderMode::Hist => bin_points(projected, settings.width, settings.height),
+        RenderMode::HistBlur => blur_image(&bin_points(projected, settings.width, settings.height),
+            settings.sigma, settings.splat_error)
+    }
+}
+
+/// Returns a u64 - the seed for one model's random numbers.
+/// Mixes the master seed with the model index (splitmix64) so each
+/// model gets its own stream, whichever thread it ends up on.
+/// # Arguments
+/// 
+/// * `seed` - A u64 - the master seed
+/// * `index` - A usize - the model index
+///
+fn model_seed(seed : u64, index : usize) -> u64 {
+    let mut z = seed.wrapping_add((index as u64).wrapping_add(1).wrapping_mul(0x9E3779B97F4A7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Returns the sum, max, mean, standard deviation and number of
+/// non-zero pixels of an image.
+/// # Arguments
+///
+/// * `img` - A Vec of Vec of f32 - the image
+///
+fn image_stats(img : &Vec<Vec<f32>>) -> (f32, f32, f32, f32, usize) {
+    let mut total : f64 = 0.0;
+    let mut total_sq : f64 = 0.0;
+    let mut peak : f32 = 0.0;
+    let mut nonzero : usize = 0;
+    let mut count : usize = 0;
+
+    for row in img {
+        for v in row {
+            total += *v as f64;
+            total_sq += (*v as f64) * (*v as f64);
+            if *v > peak { peak = *v; }
+            if *v != 0.0 { nonzero += 1; }
+            count += 1;
+        }
+    }
+
+    let mean = if count > 0 { total / count as f64 } else { 0.0 };
+    let var = if count > 0 { (total_sq / count as f64 - mean * mean).max(0.0) } else { 0.0 };
+    (total as f32, peak, mean as f32, var.sqrt() as f32, nonzero)
+}
+
+/// Returns a Result - write one image to its own FITS file, unless it
+/// is packed, and its export if asked, each under a temporary name
+/// that is renamed once the file is complete.
+/// # Arguments
+///
+/// * `timg` - A Vec of Vec of f32 - the image
+/// * `row` - A ManifestRow - the image's file and where it came from
+/// * `export_name` - A String - the image's own name, for the export
+/// * `out_path` - A String - the output directory
+/// * `settings` - A RenderSettings - the output format and export
+///
+fn save_image(timg : &Vec<Vec<f32>>, row : &ManifestRow, export_name : &String, out_path : &String,
+    settings : &RenderSettings) -> Result<(), Box<Error>> {
+    if settings.export.format.is_some() {
+        let exportpath = Path::new(out_path).join(export_name)
+            .with_extension(settings.export.extension()).to_string_lossy().to_string();
+        write_via_temp(&exportpath,
+            |tmppath| export::save_export(timg, settings.width as usize, settings.height as usize, tmppath, &settings.export))
+            .map_err(|e| format!("Error writing {}: {}", exportpath, e))?;
+    }
+
+    if settings.format == OutputFormat::Files {
+        let fitspath = Path::new(out_path).join(&row.file).to_string_lossy().to_string();
+        write_via_temp(&fitspath, |tmppath| save_fits(timg, tmppath, settings, &row.info))
+            .map_err(|e| format!("Error writing {}: {}", fitspath, e))?;
+    }
+    Ok(())
+}
+
+/// One image on its way from a render thread to the writer thread.
+/// The pixels are None if an earlier run already wrote the image.
+struct Rendered {
+    row : ManifestRow,
+    name : String,
+    ordinal : usize,
+    image : Option<Vec<Vec<f32>>>
+}
+
+/// Returns a bool - render every image of one model and pass each
+/// on to the writer thread. False if we should stop, because another
+/// thread failed or the writer has gone.
+/// # Arguments
+///
+/// * `model` - A Model - the model to render
+/// * `position` - A usize - where the model is in the list, which names its images
+/// * `settings` - A RenderSettings - sigma, scale, rotation and the rest
+/// * `completed` - A HashMap of ordinal to ImageStats - images an earlier run wrote
+/// * `cancel` - An AtomicBool - set when any thread fails
+/// * `tx` - A SyncSender of Rendered - the way to the writer thread
+///
+fn render_model(model : &Model, position : usize, settings : &RenderSettings, completed : &HashMap<usize, ImageStats>,
+    cancel : &AtomicBool, outside : &AtomicUsize, tx : &SyncSender<Rendered>) -> bool {
+    let mseed = model_seed(settings.seed, model.index);
+    let mut rng = StdRng::seed_from_u64(mseed);
+    // Drawn from the whole model so the subsamples stay put
+    let centre = match model.centre {
+        Some(c) => c,
+        None => find_centre(&model.points)
+    };
+    // The subsamples have their own generator so the rotations
+    // do not change with the subsampling.
+    let mut drop_rng = StdRng::seed_from_u64(model_seed(mseed, 0));
+    let mut jitter_rng = StdRng::seed_from_u64(model_seed(mseed, 1));
+    let keep = settings.keep_count(model.points.len());
+
+    for draw in 0..settings.draws.max(1) {
+        let kept = drop_points(&mut drop_rng, &model.points, keep);
+        let mut scaled = scale_shift_model(&kept, centre, settings);
+        normalise_weights(&mut scaled, settings.weight_norm);
+
+        // A rotation for each augment, then fresh jitter, then an
+        // orthographic projection down the z axis onto the image plane.
+        let rotations = augment_rotations(&mut rng, settings);
+        for (augment, rm) in rotations.into_iter().enumerate() {
+            if cancel.load(Ordering::SeqCst) { return false; }
+            let (jittered, jitter) = jitter_points(&mut jitter_rng, &scaled, settings);
+            let projected : Vec<(f32, f32, f32, f32)> = jittered.iter().map(|point| {
+                let rp = rm * Vector3::new(point.x, point.y, point.z);
+                (rp.x, rp.y, point_sigma(point, settings), point.weight)
+            }).collect();
+            let nrender = draw * settings.augments.max(1) + augment as u32;
+            let ordinal = position * settings.renders() as usize + nrender as usize;
+
+            // Images an earlier run wrote only need their stats
+            let (timg, (total, peak, mean, sd, nonzero)) = match completed.get(&ordinal) {
+                Some(stats) => (None, *stats),
+                None => {
+                    outside.fetch_add(outside_image(&projected, settings.width, settings.height), Ordering::SeqCst);
+                    let timg = draw_points(&projected, settings);
+                    let stats = image_stats(&timg);
+                    (Some(timg), stats)
+                }
+            };
+
+            let info = ImageInfo {
+                index : model.index,
+                id : model.id,
+                seed : mseed,
+                draw : draw,
+                augment : augment as u32,
+                jitter : jitter,
+                rotation : rm,
+                centre : centre,
+                npoints : projected.len()
+            };
+            let name = image_name(position, nrender, settings.renders());
+            let mut row = ManifestRow {
+                file : name.clone(),
+                plane : None,
+                block : None,
+                split : model.split.clone(),
+                info : info,
+                sigma : settings.sigma,
+                total : total,
+                peak : peak,
+                mean : mean,
+                sd : sd,
+                nonzero : nonzero
+            };
+
+            if settings.format != OutputFormat::Files {
+                row.file = chunk_name(ordinal / settings.chunk_size, settings.format);
+                row.plane = Some(ordinal % settings.chunk_size);
+            }
+
+            let rendered = Rendered { row : row, name : name, ordinal : ordinal, image : timg };
+            if tx.send(rendered).is_err() { return false; }
+        }
+    }
+    true
+}
+
+/// Returns a Result - write the images as they come in from the render
+/// threads, holding packed planes back until their chunk is whole. Each
+/// image goes in the journal once it is on disk and its row goes on to
+/// `done`, which moves the progress bar.
+/// # Arguments
+///
+/// * `rx` - A Receiver of Rendered - the images from the render threads
+/// * `done` - A Sender of rows - where the written rows go
+/// * `journal` - A File - the journal, open for appending
+/// * `journal_path` - A Path - the journal's name, for errors
+/// * `num_images` - A usize - how many images the whole run makes
+/// * `out_path` - A String - the output directory
+/// * `settings` - A RenderSettings - the output format and export
+///
+fn write_images(rx : Receiver<Rendered>, done : &Sender<Result<Vec<ManifestRow>, String>>, journal : &mut File,
+    journal_path : &Path, num_images : usize, out_path : &String, settings : &RenderSettings) -> Result<(), String> {
+    let mut pending : HashMap<usize, Vec<(ManifestRow, Vec<Vec<f32>>)>> = HashMap::new();
+    let journal_error = |e : std::io::Error| format!("Error writing {}: {}", journal_path.display(), e);
+
+    for rendered in rx.iter() {
+        let Rendered { row, name, ordinal, image } = rendered;
+        let timg = match image {
+            Some(timg) => timg,
+            None => {
+                let _ = done.send(Ok(vec![row]));
+                continue;
+            }
+        };
+        save_image(&timg, &row, &name, out_path, settings).map_err(|e| e.to_string())?;
+
+        if settings.format == OutputFormat::Files {
+            journal.write_all(journal_line(&row).as_bytes()).map_err(journal_error)?;
+            let _ = done.send(Ok(vec![row]));
+            continue;
+        }
+
+        let chunk = ordinal / settings.chunk_size;
+        let expected = settings.chunk_size.min(num_images - chunk * settings.chunk_size);
+        let full = {
+            let planes = pending.entry(chunk).or_insert(vec![]);
+            planes.push((row, timg));
+            planes.len() == expected
+        };
+        if full {
+            let mut planes = pending.remove(&chunk).unwrap();
+            planes.sort_by_key(|(row, _)| row.plane);
+            let chunkpath = Path::new(out_path).join(chunk_name(chunk, settings.format)).to_string_lossy().to_string();
+            write_via_temp(&chunkpath, |tmppath| save_chunk(&planes, tmppath, settings))
+                .map_err(|e| format!("Error writing {}: {}", chunkpath, e))?;
+            let lines : String = planes.iter().map(|(row, _)| journal_line(row)).collect();
+            journal.write_all(lines.as_bytes()).map_err(journal_error)?;
+            let _ = done.send(Ok(planes.into_iter().map(|(row, _)| row).collect()));
+        }
+    }
+    Ok(())
+}
+
+/// Returns a Result of a Vec of ManifestRow - one for each image
+/// rendered, in the order of the image files. The render threads
+/// take one model at a time off a shared queue, so big models do not
+/// hold up the rest, and one writer thread does all the writing.
+/// The first error stops the other threads and comes back here.
+/// # Arguments
+///
+/// * `models` - A Vec of Model
+/// * `out_path` - A String representing the path to render to
+/// * `nthreads` - A u32 - the number of threads to spin up
+/// * `settings` - A RenderSettings - sigma, scale, rotation and the rest
+///
+fn render (models : &Vec<Model>, out_path : &String,  nthreads : u32, settings : &RenderSettings) -> Result<Vec<ManifestRow>, Box<Error>> {
+    // The render threads send to the writer, which sends on to us. The
+    // first channel is bounded so rendering cannot run far ahead of the disk.
+    let (tx, rx) = sync_channel(nthreads as usize * 2);
+    let (done_tx, done_rx) = channel();
+    let mut pool = Pool::new(nthreads + 1);
+    // Set on the first error so the other threads stop early
+    let cancel = AtomicBool::new(false);
+    // The next model to render
+    let next = AtomicUsize::new(0);
+    // Points that land off the edges of their image
+    let outside = AtomicUsize::new(0);
+
+    let num_images = models.len() as u32 * settings.renders();
+    let mut pb = ProgressBar::new(num_images as u64);
+    pb.format("╢▌▌░╟");
+    let mut rows : Vec<ManifestRow> = vec![];
+    let mut failure : Option<String> = None;
+
+    // The journal lists every image once it is safely written, so a
+    // resumed run can skip it. Everything is still drawn from the same
+    // generators in the same order, so the rest come out the same.
+    let journal_path = Path::new(out_path).join("completed.csv");
+    let fingerprint = run_fingerprint(models, settings);
+    let mut completed : HashMap<usize, ImageStats> = HashMap::new();
+    if settings.resume && journal_path.exists() {
+        let journal = read_journal(&journal_path, &fingerprint)
+            .map_err(|e| format!("Cannot resume: {}", e))?;
+        completed = completed_images(num_images as usize, out_path, settings, &journal);
+        println!("Resuming: {} of {} images already done", completed.len(), num_images);
+    }
+    let mut journal = fs::OpenOptions::new().create(true).append(true).open(&journal_path)
+        .map_err(|e| format!("Error opening {}: {}", journal_path.display(), e))?;
+    let started = if completed.len() == 0 {
+        let header = format!("# {}\nfile,plane,sum,max,mean,sd,nonzero", fingerprint);
+        journal.set_len(0).and_then(|_| writeln!(journal, "{}", header))
+    } else {
+        // Cut off a line a crash left short, as it was not read either
+        match fs::read(&journal_path) {
+            Ok(bytes) => {
+                let complete = bytes.iter().rposition(|b| *b == b'\n').map_or(0, |p| p + 1);
+                journal.set_len(complete as u64)
+            },
+            Err(e) => Err(e)
+        }
+    };
+    started.map_err(|e| format!("Error writing {}: {}", journal_path.display(), e))?;
+    let completed = &completed;
+    let cancel = &cancel;
+    let next = &next;
+    let outside_count = &outside;
+    let journal_path = &journal_path;
+
+    // Each thread gets its own sender, so the channel closes once they all finish
+    let senders : Vec<_> = (0..nthreads).map(|_| tx.clone()).collect();
+    drop(tx);
+
+    pool.scoped(|scoped| {
+        for tx in senders.into_iter() {
+            scoped.execute( move || {
+                // Take the next model off the queue until there are none left
+                loop {
+                    let position = next.fetch_add(1, Ordering::SeqCst);
+                    if position >= models.len() { break; }
+                    if !render_model(&models[position], position, settings, completed, cancel, outside_count, &tx) { break; }
+                }
+            });
+        }
+
+        scoped.execute( move || {
+            if let Err(e) = write_images(rx, &done_tx, &mut journal, journal_path, num_images as usize, out_path, settings) {
+                cancel.store(true, Ordering::SeqCst);
+                let _ = done_tx.send(Err(e));
+            }
+        });
+
+        // Update our progress bar as each image is written, until the writer finishes
+        for message in done_rx.iter() {
+            match message {
+                Ok(written) => {
+                    pb.add(written.len() as u64);
+                    rows.extend(written);
+                },
+                Err(e) => failure = Some(e)
+            }
+        }
+    });
+    // The bar leaves the cursor at the end of its line
+    println!();
+    let outside = outside.load(Ordering::SeqCst);
+    if outside > 0 {
+        println!("Warning: {} points fell outside their images and were left out. --fit keeps every model inside.",
+            outside);
+    }
+
+    if let Some(e) = failure {
+        return Err(From::from(e));
+    }
+    if rows.len() < num_images as usize {
+        return Err(From::from(format!("Only {} of {} images were rendered", rows.len(), num_images)));
+    }
+    rows.sort_by(|a, b| (&a.file, a.plane).cmp(&(&b.file, b.plane)));
+    Ok(rows)
+}
+
+/// Returns a Result of a Vec of ManifestRow - render the models and, if the
+/// splits are laid out as directories, render each split into its
+/// own directory, named after the split.
+/// # Arguments
+/// 
+/// * `models` - A Vec of Model - the models, with their splits
+/// * `out_path` - A String - the output directory
+/// * `nthreads` - A u32 - the number of threads
+/// * `settings` - A RenderSettings - the render settings
+/// * `layout` - A SplitLayout - how the splits are laid out
+///
+fn render_splits(models : &Vec<Model>, out_path : &String, nthreads : u32, settings : &RenderSettings,
+    layout : SplitLayout) -> Result<Vec<ManifestRow>, Box<Error>> {
+    if layout != SplitLayout::Dirs || models.iter().all(|m| m.split.is_none()) {
+        return render(models, out_path, nthreads, settings);
+    }
+
+    let names = split::split_names(models.iter().filter_map(|m| m.split.clone()).collect());
+    let mut rows : Vec<ManifestRow> = vec![];
+
+    for name in names {
+        let split_models : Vec<Model> = models.iter()
+            .filter(|m| m.split.as_ref() == Some(&name)).cloned().collect();
+        let split_path = Path::new(out_path).join(&name);
+        fs::create_dir_all(&split_path)
+            .map_err(|e| format!("Error creating {}: {}", split_path.display(), e))?;
+        for mut row in render(&split_models, &split_path.to_string_lossy().to_string(), nthreads, settings)? {
+            row.file = format!("{}/{}", name, row.file);
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+/// Returns a Result of an i64 - the model ID.
+/// IDs are sometimes written out as floats (e.g. 12.0) so we
+/// accept those as long as there is no fractional part.
+/// # Arguments
+/// 
+/// * `field` - A str - the ID field from the CSV
+///
+fn parse_id(field : &str) -> Result<i64, Box<Error>> {
+    let field = field.trim();
+    if let Ok(id) = field.parse::<i64>() { return Ok(id); }
+    let fid : f64 = field.parse()?;
+    if fid.fract() != 0.0 {
+        return Err(From::from(format!("Model ID {} is not a whole number", field)));
+    }
+    Ok(fid as i64)
+}
+
+/// Returns a Result of Vec of Model and the Columns that were read.
+/// Parse the CSV file, grouping the rows into models by their ID.
+/// Columns are found by their header name. Models are returned in
+/// ascending order of ID. If there is no ID column, all the points
+/// end up in a single model.
+/// # Arguments
+/// 
+/// * `path` - A String - the path to the CSV file
+/// * `aliases` - A ColumnAliases - the header names to look for
+///
+fn parse_csv(path : &String, aliases : &ColumnAliases) -> Result<(Vec<Model>, Columns), Box<Error>> {
+    let file = File::open(path)?;
+    let mut rdr = csv::Reader::from_reader(file);
+    let columns = map_columns(rdr.headers()?, aliases)?;
+    let mut grouped : BTreeMap<i64, Vec<Point>> = BTreeMap::new();
+
+    for (row, result) in rdr.records().enumerate() {
+        let record = result?;
+        // The header is line 1, so the first row is line 2
+        let line = row + 2;
+        //println!("{:?}", record);
+        let x: f32 = parse_field(&record, columns.x, line)?;
+        let y: f32 = parse_field(&record, columns.y, line)?;
+        let mut z: f32 = 0.0;
+        if let Some(c) = columns.z { z = parse_field(&record, c, line)?; }
+        let mut sigma: f32 = 0.0;
+        if let Some(c) = columns.sigma {
+            // A blank precision is a missing one, so the point takes the global sigma
+            match record.get(c) {
+                Some(field) if field.trim().len() == 0 => {},
+                _ => { sigma = parse_field(&record, c, line)?; }
+            }
+        }
+        let mut weight: f32 = 1.0;
+        if let Some(c) = columns.weight { weight = parse_field(&record, c, line)?; }
+        let mut photons: f32 = 1.0;
+        if let Some(c) = columns.photons { photons = parse_field(&record, c, line)?; }
+        let mut frame: u32 = 0;
+        if let Some(c) = columns.frame { frame = parse_frame(&record, c, line)?; }
+        let mut id : i64 = 0;
+
+        if let Some(c) = columns.id {
+            match record.get(c) {
+                Some(field) => { id = parse_id(field).map_err(|e| format!("Line {}: {}", line, e))?; },
+                None => {
+                    return Err(From::from(format!("Line {} has no column {}", line, c)));
+                }
+            }
+        }
+
+        let p = Point {
+            x : x,
+            y : y,
+            z : z,
+            sigma : sigma,
+            weight : weight,
+            photons : photons,
+            frame : frame
+        };
+        grouped.entry(id).or_insert(vec![]).push(p);
+    }
+
+    let models : Vec<Model> = grouped.into_iter().enumerate()
+        .map(|(index, (id, points))| Model {
+            index : index, id : id, points : points, centre : None, split : None
+        }).collect();
+    Ok((models, columns))
+}
+
+/// Returns a Vec of Model - the models with only the points that fall
+/// in the frame window. Models keep their index and ID. Models the
+/// window leaves empty are dropped, so they do not render as blanks.
+/// # Arguments
+/// 
+/// * `models` - A Vec of Model
+/// * `start` - An Option of u32 - the first frame to keep
+/// * `end` - An Option of u32 - the last frame to keep
+///
+fn filter_frames(models : &Vec<Model>, start : Option<u32>, end : Option<u32>) -> Vec<Model> {
+    models.iter().map(|model| {
+        let points : Vec<Point> = model.points.iter().filter(|p| {
+            start.map_or(true, |s| p.frame >= s) && end.map_or(true, |e| p.frame <= e)
+        }).cloned().collect();
+        Model { points : points, ..model.clone() }
+    }).filter(|model| model.points.len() > 0).collect()
+}
+
+/// Returns a Vec of Vec of Model - the models split into time blocks.
+/// The frame range of all the points is cut into `nblocks` equal
+/// ranges. Each block holds every model, with only the points from
+/// its range, centred where the whole model is centred so the blocks
+/// line up with each other.
+/// # Arguments
+/// 
+/// * `models` - A Vec of Model
+/// * `nblocks` - A usize - the number of blocks
+///
+fn split_blocks(models : &Vec<Model>, nblocks : usize) -> Vec<Vec<Model>> {
+    let mut fmin : u32 = u32::max_value();
+    let mut fmax : u32 = 0;
+
+    for model in models {
+        for point in &model.points {
+            if point.frame < fmin { fmin = point.frame; }
+            if point.frame > fmax { fmax = point.frame; }
+        }
+    }
+
+    let span = (fmax - fmin.min(fmax)) as u64 + 1;
+    let mut blocks : Vec<Vec<Model>> = vec![];
+
+    for b in 0..nblocks {
+        let bstart = fmin as u64 + span * b as u64 / nblocks as u64;
+        let bend = fmin as u64 + span * (b as u64 + 1) / nblocks as u64;
+        println!("Time block {} : frames {} to {}", b, bstart, bend as i64 - 1);
+
+        let block : Vec<Model> = models.iter().map(|model| {
+            let points : Vec<Point> = model.points.iter().filter(|p| {
+                p.frame as u64 >= bstart && (p.frame as u64) < bend
+            }).cloned().collect();
+            Model {
+                points : points,
+                centre : Some(find_centre(&model.points)),
+                ..model.clone()
+            }
+        }).collect();
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// Returns an f64 - the polynomial with coefficients `coeffs` at `t`.
+/// # Arguments
+/// 
+/// * `coeffs` - A Vec of f64 - lowest order first
+/// * `t` - An f64 - where to evaluate
+///
+fn eval_polynomial(coeffs : &Vec<f64>, t : f64) -> f64 {
+    coeffs.iter().rev().fold(0.0, |acc, c| acc * t + c)
+}
+
+/// Returns an Option of Vec of f64 - the coefficients, lowest order
+/// first, of the least squares polynomial through the values.
+/// # Arguments
+/// 
+/// * `ts` - A Vec of f64 - the times
+/// * `vs` - A Vec of f64 - the values
+/// * `degree` - A usize - the degree of the polynomial
+///
+fn fit_polynomial(ts : &Vec<f64>, vs : &Vec<f64>, degree : usize) -> Option<Vec<f64>> {
+    let a = DMatrix::from_fn(ts.len(), degree + 1, |i, j| ts[i].powi(j as i32));
+    let b = DVector::from_iterator(vs.len(), vs.iter().cloned());
+    let at = a.transpose();
+    (&at * &a).lu().solve(&(&at * &b)).map(|c| c.iter().cloned().collect())
+}
+
+/// Returns an f32 - the sub-bin offset of a peak from the values
+/// either side of it, by fitting a parabola.
+/// # Arguments
+/// 
+/// * `l` - An f32 - the value before the peak
+/// * `c` - An f32 - the value at the peak
+/// * `r` - An f32 - the value after the peak
+///
+fn parabolic_offset(l : f32, c : f32, r : f32) -> f32 {
+    let denom = l - 2.0 * c + r;
+    if denom == 0.0 { return 0.0; }
+    (0.5 * (l - r) / denom).max(-0.5).min(0.5)
+}
+
+/// Returns an Option of a tuple of two f32 - the shift in bins that
+/// best lines `b` up with `a`, found from the peak of their cross
+/// correlation within `max` bins. Only the filled bins of `a` are
+/// visited, each against a dense window of `b`. The correlation is
+/// smoothed a little before we look for the peak.
+/// # Arguments
+///
+/// * `a` - A Vec of bin and count - the filled bins of the reference
+/// * `b` - A Vec of f32 - the render to line up, a dense grid
+/// * `width` - A usize - the width of the grid
+/// * `max` - An i32 - the largest shift to try, in bins
+///
+fn correlate_2d(a : &Vec<(usize, f32)>, b : &Vec<f32>, width : usize, max : i32) -> Option<(f32, f32)> {
+    let side = (2 * max + 1) as usize;
+    let height = b.len() / width;
+    let mut cc = vec![0f32; side * side];
+
+    for &(idx, va) in a {
+        let (i, j) = ((idx % width) as i32, (idx / width) as i32);
+        // Only the shifts that stay on the grid
+        let dx0 = (-max).max(-i);
+        let dx1 = max.min(width as i32 - 1 - i);
+        for dy in (-max).max(-j)..(max.min(height as i32 - 1 - j) + 1) {
+            let row = ((j + dy) as usize) * width;
+            let ccrow = (dy + max) as usize * side;
+            for dx in dx0..(dx1 + 1) {
+                cc[ccrow + (dx + max) as usize] += va * b[row + (i + dx) as usize];
+            }
+        }
+    }
+
+    let kernel = [1.0, 2.0, 1.0];
+    let mut smooth = vec![0f32; side * side];
+    for y in 0..side {
+        for x in 0..side {
+            for ky in 0..3 {
+                for kx in 0..3 {
+                    let sy = y as i32 + ky as i32 - 1;
+                    let sx = x as i32 + kx as i32 - 1;
+                    if sy >= 0 && sx >= 0 && (sy as usize) < side && (sx as usize) < side {
+                        smooth[y * side + x] += kernel[ky] * kernel[kx] * cc[sy as usize * side + sx as usize];
+                    }
+                }
+            }
+        }
+    }
+
+    let mut best = 0;
+    for idx in 0..smooth.len() {
+        if smooth[idx] > smooth[best] { best = idx; }
+    }
+    if smooth[best] <= 0.0 { return None; }
+
+    let (px, py) = (best % side, best / side);
+    let mut ox = 0.0;
+    let mut oy = 0.0;
+    if px > 0 && px < side - 1 {
+        ox = parabolic_offset(smooth[best - 1], smooth[best], smooth[best + 1]);
+    }
+    if py > 0 && py < side - 1 {
+        oy = parabolic_offset(smooth[best - side], smooth[best], smooth[best + side]);
+    }
+    Some((px as f32 - max as f32 + ox, py as f32 - max as f32 + oy))
+}
+
+/// Returns an Option of f32 - the shift in bins that best lines `b`
+/// up with `a`, as correlate_2d but for one dimension.
+/// # Arguments
+///
+/// * `a` - A Vec of f32 - the reference histogram
+/// * `b` - A Vec of f32 - the histogram to line up, the same length
+/// * `max` - An i32 - the largest shift to try, in bins
+///
+fn correlate_1d(a : &Vec<f32>, b : &Vec<f32>, max : i32) -> Option<f32> {
+    let side = (2 * max + 1) as usize;
+    let mut cc = vec![0f32; side];
+
+    for (i, &va) in a.iter().enumerate() {
+        if va == 0.0 { continue; }
+        for d in -max..(max + 1) {
+            let k = i as i32 + d;
+            if k >= 0 && (k as usize) < b.len() {
+                cc[(d + max) as usize] += va * b[k as usize];
+            }
+        }
+    }
+
+    let mut smooth = vec![0f32; side];
+    for x in 0..side {
+        smooth[x] = 2.0 * cc[x];
+        if x > 0 { smooth[x] += cc[x - 1]; }
+        if x < side - 1 { smooth[x] += cc[x + 1]; }
+    }
+
+    let mut best = 0;
+    for idx in 0..side {
+        if smooth[idx] > smooth[best] { best = idx; }
+    }
+    if smooth[best] <= 0.0 { return None; }
+
+    let mut o = 0.0;
+    if best > 0 && best < side - 1 {
+        o = parabolic_offset(smooth[best - 1], smooth[best], smooth[best + 1]);
+    }
+    Some(best as f32 - max as f32 + o)
+}
+
+/// Returns a Result of the DriftCurve and the drift measured in each
+/// window as (centre frame, x, y, z).
+/// The points of all the models are cut into time windows. Each
+/// window is rendered as a histogram and cross correlated with the
+/// window before it that could be lined up, and the shifts are added
+/// up from the first window, which has no drift. A polynomial in time
+/// is then fitted through the summed shifts.
+/// # Arguments
+///
+/// * `models` - A Vec of Model - the whole acquisition
+/// * `settings` - A DriftSettings - windows, bin size and the fit
+/// * `use_z` - A bool - estimate the drift in z as well
+///
+fn estimate_drift(models : &Vec<Model>, settings : &DriftSettings, use_z : bool) ->
+    Result<(DriftCurve, Vec<(f32, f32, f32, f32)>), Box<Error>> {
+    let mut fmin : u32 = u32::max_value();
+    let mut fmax : u32 = 0;
+    let (mut xmin, mut ymin, mut zmin) = (i32::max_value(), i32::max_value(), i32::max_value());
+    let (mut xmax, mut ymax, mut zmax) = (i32::min_value(), i32::min_value(), i32::min_value());
+    let bin = |v : f32| (v / settings.pixel).floor() as i32;
+
+    for model in models {
+        for point in &model.points {
+            if point.frame < fmin { fmin = point.frame; }
+            if point.frame > fmax { fmax = point.frame; }
+            xmin = xmin.min(bin(point.x));
+            xmax = xmax.max(bin(point.x));
+            ymin = ymin.min(bin(point.y));
+            ymax = ymax.max(bin(point.y));
+            zmin = zmin.min(bin(point.z));
+            zmax = zmax.max(bin(point.z));
+        }
+    }
+    if fmin > fmax { return Err(From::from("No points to estimate the drift from")); }
+
+    // One grid of bins covers every window
+    let width = (xmax - xmin + 1) as usize;
+    let height = (ymax - ymin + 1) as usize;
+    let depth = (zmax - zmin + 1) as usize;
+    if (width as u64) * (height as u64) > 1 << 28 {
+        return Err(From::from(format!("The drift histogram would be {} x {} bins - use a larger --drift-pixel",
+            width, height)));
+    }
+
+    let nwin = settings.windows.max(2);
+    let span = (fmax - fmin) as u64 + 1;
+    let mut filled : Vec<Vec<usize>> = vec![vec![]; nwin];
+    let mut zhists : Vec<Vec<f32>> = vec![vec![0.0; depth]; nwin];
+
+    for model in models {
+        for point in &model.points {
+            let w = ((point.frame - fmin) as u64 * nwin as u64 / span) as usize;
+            filled[w].push((bin(point.y) - ymin) as usize * width + (bin(point.x) - xmin) as usize);
+            zhists[w][(bin(point.z) - zmin) as usize] += 1.0;
+        }
+    }
+
+    // Each window as a list of filled bins and their counts
+    let hists : Vec<Vec<(usize, f32)>> = filled.into_iter().map(|mut bins| {
+        bins.sort();
+        let mut counts : Vec<(usize, f32)> = vec![];
+        for b in bins {
+            match counts.last_mut() {
+                Some(last) if last.0 == b => last.1 += 1.0,
+                _ => counts.push((b, 1.0))
+            }
+        }
+        counts
+    }).collect();
+
+    let max = (settings.max_shift / settings.pixel).ceil() as i32;
+    let mut estimates : Vec<(f32, f32, f32, f32)> = vec![];
+    let mut dense = vec![0f32; width * height];
+    // The last window we lined up and its drift, in bins
+    let mut reference : Option<(usize, f32, f32, f32)> = None;
+
+    for w in 0..nwin {
+        let centre = fmin as f32 + (span as f32 * (w as f32 + 0.5) / nwin as f32);
+        if hists[w].len() == 0 { continue; }
+
+        let (r, rx, ry, rz) = match reference {
+            Some(r) => r,
+            None => {
+                reference = Some((w, 0.0, 0.0, 0.0));
+                estimates.push((centre, 0.0, 0.0, 0.0));
+                continue;
+            }
+        };
+
+        for &(idx, count) in &hists[w] { dense[idx] = count; }
+        let shift = correlate_2d(&hists[r], &dense, width, max);
+        for &(idx, _) in &hists[w] { dense[idx] = 0.0; }
+
+        if let Some((sx, sy)) = shift {
+            let mut sz = 0.0;
+            if use_z {
+                if let Some(s) = correlate_1d(&zhists[r], &zhists[w], max) { sz = s; }
+            }
+            let drift = (w, rx + sx, ry + sy, rz + sz);
+            estimates.push((centre, drift.1 * settings.pixel, drift.2 * settings.pixel, drift.3 * settings.pixel));
+            reference = Some(drift);
+        } else {
+            println!("Drift window {} does not overlap window {} - skipping it", w, r);
+        }
+    }
+
+    if estimates.len() < 2 {
+        return Err(From::from("Too few time windows could be lined up to estimate the drift"));
+    }
+
+    let degree = settings.degree.min(estimates.len() - 1);
+    let mut curve = DriftCurve { fmin : fmin, fmax : fmax, cx : vec![], cy : vec![], cz : vec![] };
+    let ts : Vec<f64> = estimates.iter().map(|e| {
+        if fmax > fmin { 2.0 * (e.0 as f64 - fmin as f64) / (fmax - fmin) as f64 - 1.0 } else { 0.0 }
+    }).collect();
+    let fit = |vs : Vec<f64>| fit_polynomial(&ts, &vs, degree)
+        .ok_or_else(|| -> Box<Error> { From::from("Could not fit the drift curve") });
+
+    curve.cx = fit(estimates.iter().map(|e| e.1 as f64).collect())?;
+    curve.cy = fit(estimates.iter().map(|e| e.2 as f64).collect())?;
+    curve.cz = fit(estimates.iter().map(|e| e.3 as f64).collect())?;
+    Ok((curve, estimates))
+}
+
+/// Returns None
+/// Take the drift away from every point in place.
+/// # Arguments
+/// 
+/// * `models` - A Vec of Model
+/// * `curve` - A DriftCurve - the drift over time
+/// * `use_z` - A bool - correct z as well
+///
+fn apply_drift(models : &mut Vec<Model>, curve : &DriftCurve, use_z : bool) {
+    for model in models.iter_mut() {
+        for point in model.points.iter_mut() {
+            let (dx, dy, dz) = curve.at(point.frame);
+            point.x -= dx;
+            point.y -= dy;
+            if use_z { point.z -= dz; }
+        }
+    }
+}
+
+/// Returns a Result of None.
+/// Write the fitted drift for every frame to drift.csv, and the drift
+/// measured in each window to drift_windows.csv.
+/// # Arguments
+/// 
+/// * `out_path` - A String - the output directory
+/// * `curve` - A DriftCurve - the fitted drift
+/// * `estimates` - A Vec of (frame, x, y, z) - the measured drift
+///
+fn write_drift(out_path : &String, curve : &DriftCurve, estimates : &Vec<(f32, f32, f32, f32)>) -> Result<(), Box<Error>> {
+    let mut wtr = csv::Writer::from_path(Path::new(out_path).join("drift.csv"))?;
+    wtr.write_record(&["frame", "dx", "dy", "dz"])?;
+    for frame in curve.fmin..(curve.fmax + 1) {
+        let (dx, dy, dz) = curve.at(frame);
+        wtr.write_record(&[frame.to_string(), dx.to_string(), dy.to_string(), dz.to_string()])?;
+    }
+    wtr.flush()?;
+
+    let mut wtr = csv::Writer::from_path(Path::new(out_path).join("drift_windows.csv"))?;
+    wtr.write_record(&["frame", "dx", "dy", "dz"])?;
+    for e in estimates {
+        wtr.write_record(&[e.0.to_string(), e.1.to_string(), e.2.to_string(), e.3.to_string()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Returns a Point - the photon weighted average of a group of
+/// points that are the same blinking event. The precisions combine
+/// as independent measurements, and the weights and photons add up.
+/// # Arguments
+/// 
+/// * `points` - A Vec of Point - the points of one event
+///
+fn merge_event(points : &Vec<Point>) -> Point {
+    let mut total : f32 = points.iter().map(|p| p.photons.max(0.0)).sum();
+    let uniform = !(total > 0.0);
+    if uniform { total = points.len() as f32; }
+    let mut merged = points[0];
+    merged.x = 0.0;
+    merged.y = 0.0;
+    merged.z = 0.0;
+
+    for point in points {
+        let w = if uniform { 1.0 } else { point.photons.max(0.0) };
+        merged.x += point.x * w / total;
+        merged.y += point.y * w / total;
+        merged.z += point.z * w / total;
+    }
+
+    if points.iter().all(|p| p.sigma > 0.0) {
+        let inv : f32 = points.iter().map(|p| 1.0 / (p.sigma * p.sigma)).sum();
+        merged.sigma = 1.0 / inv.sqrt();
+    }
+    merged.weight = points.iter().map(|p| p.weight).sum();
+    merged.photons = points.iter().map(|p| p.photons).sum();
+    merged
+}
+
+/// Returns a Vec of Point - the model with repeated localisations of
+/// the same blinking event merged together.
+/// Points are taken in frame order. A point joins the nearest event
+/// within `distance` that was last seen no more than `gap` frames
+/// before (and not in the same frame), otherwise it starts a new one.
+/// # Arguments
+/// 
+/// * `points` - A Vec of Point - the model
+/// * `distance` - An f32 - the largest distance between repeats
+/// * `gap` - A u32 - the number of dark frames allowed between repeats
+///
+fn merge_blinks(points : &Vec<Point>, distance : f32, gap : u32) -> Vec<Point> {
+    let mut sorted = points.clone();
+    sorted.sort_by_key(|p| p.frame);
+
+    // Each event is its points and its running centre
+    let mut events : Vec<(Vec<Point>, f32, f32)> = vec![];
+    let mut active : Vec<usize> = vec![];
+
+    for point in sorted {
+        active.retain(|&e| {
+            let last = events[e].0.last().unwrap().frame;
+            point.frame - last <= gap.saturating_add(1)
+        });
+
+        let mut best : Option<(usize, f32)> = None;
+        for &e in &active {
+            if events[e].0.last().unwrap().frame == point.frame { continue; }
+            let dx = events[e].1 - point.x;
+            let dy = events[e].2 - point.y;
+            let d = (dx * dx + dy * dy).sqrt();
+            if d <= distance && best.map_or(true, |(_, bd)| d < bd) {
+                best = Some((e, d));
+            }
+        }
+
+        match best {
+            Some((e, _)) => {
+                let n = events[e].0.len() as f32;
+                events[e].1 = (events[e].1 * n + point.x) / (n + 1.0);
+                events[e].2 = (events[e].2 * n + point.y) / (n + 1.0);
+                events[e].0.push(point);
+            },
+            None => {
+                events.push((vec![point], point.x, point.y));
+                active.push(events.len() - 1);
+            }
+        }
+    }
+
+    events.iter().map(|e| merge_event(&e.0)).collect()
+}
+
+/// Returns a Result of None.
+/// Write out how many points were merged in each model to merged.csv.
+/// # Arguments
+/// 
+/// * `out_path` - A String - the output directory
+/// * `counts` - A Vec of (model index, model id, points before, points after)
+///
+fn write_merged(out_path : &String, counts : &Vec<(usize, i64, usize, usize)>) -> Result<(), Box<Error>> {
+    let mut wtr = csv::Writer::from_path(Path::new(out_path).join("merged.csv"))?;
+    wtr.write_record(&["index", "id", "before", "after", "merged"])?;
+    for c in counts {
+        wtr.write_record(&[c.0.to_string(), c.1.to_string(), c.2.to_string(),
+            c.3.to_string(), (c.2 - c.3).to_string()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Returns a Result of Vec of usize - the accepted model indices.
+/// Read a list of model indices, one per line. Anything after a '#'
+/// is a comment and blank lines are skipped.
+/// # Arguments
+/// 
+/// * `path` - A String - the path to the accepted list
+///
+fn read_accepted(path : &String) -> Result<Vec<usize>, Box<Error>> {
+    let file = File::open(path)?;
+    let mut accepted : Vec<usize> = vec![];
+
+    for (lidx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let entry = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => &line[..]
+        }.trim();
+        if entry.len() == 0 { continue; }
+        match entry.parse::<usize>() {
+            Ok(idx) => accepted.push(idx),
+            Err(e) => {
+                return Err(From::from(format!("{} line {}: cannot parse '{}' as an index: {}",
+                    path, lidx + 1, entry, e)));
+            }
+        }
+    }
+    Ok(accepted)
+}
+
+/// Returns a Result of None.
+/// Write out the indices of the models we rendered, in the same
+/// format as the accepted list so it can be read back in. The
+/// model ID and image name follow as a comment.
+/// # Arguments
+/// 
+/// * `models` - A Vec of Model - the models that were rendered
+/// * `rows` - A Vec of ManifestRow - the images rendered
+/// * `out_path` - A String - the output directory
+///
+fn write_rendered(models : &Vec<Model>, rows : &Vec<ManifestRow>, out_path : &String) -> Result<(), Box<Error>> {
+    let mut file = File::create(Path::new(out_path).join("rendered.txt"))?;
+    writeln!(file, "# model index # model id, images")?;
+
+    // Packed images are named by their file and plane
+    let mut names : HashMap<usize, Vec<String>> = HashMap::new();
+    for row in rows {
+        let name = match row.plane {
+            Some(p) => format!("{}[{}]", row.file, p),
+            None => row.file.clone()
+        };
+        names.entry(row.info.index).or_insert(vec![]).push(name);
+    }
+
+    for model in models.iter() {
+        let images = names.get(&model.index).map(|n| n.join(" ")).unwrap_or(String::new());
+        writeln!(file, "{} # {}, {}", model.index, model.id, images)?;
+    }
+    Ok(())
+}
+
+/// Returns a String - text quoted and escaped for JSON.
+/// # Arguments
+///
+/// * `text` - A str - the text to quote
+///
+fn json_string(text : &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns a Result - write manifest.csv and manifest.json, with
+/// one row or object for every image rendered.
+/// # Arguments
+///
+/// * `rows` - A Vec of ManifestRow - the rendered images
+/// * `out_path` - A String - the output directory
+///
+fn write_manifest(rows : &Vec<ManifestRow>, out_path : &String) -> Result<(), Box<Error>> {
+    let mut wtr = csv::Writer::from_path(Path::new(out_path).join("manifest.csv"))?;
+    if rows.len() > 0 {
+        wtr.write_record(rows[0].fields().iter().map(|(k, _)| *k))?;
+    }
+    for row in rows {
+        wtr.write_record(row.fields().iter().map(|(_, v)| v.to_string()))?;
+    }
+    wtr.flush()?;
+
+    let mut file = File::create(Path::new(out_path).join("manifest.json"))?;
+    writeln!(file, "[")?;
+    for (i, row) in rows.iter().enumerate() {
+        let entries : Vec<String> = row.fields().iter().map(|(k, v)| {
+            let value = match v {
+                ManifestValue::Real(r) if !r.is_finite() => String::from("null"),
+                ManifestValue::Missing => String::from("null"),
+                ManifestValue::Text(t) => json_string(t),
+                _ => v.to_string()
+            };
+            format!("{}: {}", json_string(k), value)
+        }).collect();
+        let sep = if i + 1 < rows.len() { "," } else { "" };
+        writeln!(file, "  {{{}}}{}", entries.join(", "), sep)?;
+    }
+    writeln!(file, "]")?;
+    Ok(())
+}
+
+fn main() {
+    let mut csv_path = String::new();
+    let mut out_path = String::new();
+    let mut nthreads : u32 = 1;
+    let mut sigma : f32 = 1.8;
+    let mut x_aliases = String::new();
+    let mut y_aliases = String::new();
+    let mut z_aliases = String::new();
+    let mut sigma_aliases = String::new();
+    let mut sigma_mode = SigmaMode::Global;
+    let mut sigma_mult : f32 = 1.0;
+    let mut sigma_min : Option<f32> = None;
+    let mut sigma_max : Option<f32> = None;
+    let mut weighting = Weighting::Unit;
+    let mut weight_aliases = String::new();
+    let mut weight_norm = WeightNorm::None;
+    let mut id_aliases = String::new();
+    let mut accepted_path = String::new();
+    let mut frame_aliases = String::new();
+    let mut frame_start : Option<u32> = None;
+    let mut frame_end : Option<u32> = None;
+    let mut time_blocks : usize = 1;
+    let mut drift = false;
+    let mut merge_distance : Option<f32> = None;
+    let mut merge_gap : u32 = 1;
+    let mut splat_error : f32 = 1e-5;
+    let mut width : u32 = 1280;
+    let mut height : Option<u32> = None;
+    let mut margin : f32 = 0.05;
+    let mut fit = false;
+    let mut seed : Option<u64> = None;
+    let mut photon_aliases = String::new();
+    let mut drift_settings = DriftSettings {
+        windows : 10,
+        pixel : 20.0,
+        max_shift : 500.0,
+        degree : 3
+    };
+    let mut rotation = RotationMode::Plane;
+    let mut max_tilt : f32 = 90.0;
+    let mut augments : u32 = 1;
     let mut max_points : usize = 0;
+    let mut keep_fraction : f32 = 1.0;
+    let mut draws : u32 = 1;
+    let mut jitter : Option<f32> = None;
+    let mut jitter_precision = false;
+    let mut mode = RenderMode::Gauss;
+    let mut format = OutputFormat::Files;
+    let mut dtype = Dtype::Float32;
+    let mut split_settings = SplitSettings::new();
+    let mut resume = false;
+    let mut chunk_size : usize = 100;
+    let mut export_settings = ExportSettings::new();
+    let mut angles = AngleMode::Random;
+    let weight_help = format!("Comma separated header names for the weight column (default: {})", PHOTON_ALIASES);
+    let photon_help = format!("Comma separated header names for the photon count, used when merging (default: {})",
+        PHOTON_ALIASES);
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Render the nuclear pore models in a CSV file to FITS images.");
+        ap.refer(&mut csv_path)
+            .add_argument("csv", Store, "Path to the CSV file").required();
+        ap.refer(&mut out_path)
+            .add_argument("output", Store, "Path to the output directory").required();
+        ap.refer(&mut nthreads)
+            .add_argument("threads", Store, "Number of threads").required();
+        ap.refer(&mut sigma)
+            .add_argument("sigma", Store, "Sigma of the Gaussian for each point, in pixels").required();
+        ap.refer(&mut sigma_mode)
+            .add_option(&["--sigma-mode"], Store,
+            "global uses sigma for every point, column uses each point's precision (default: global)");
+        ap.refer(&mut sigma_aliases)
+            .add_option(&["--sigma-column"], Store,
+            "Comma separated header names for the precision column (default: uncertainty_xy [nm],uncertainty [nm],uncertainty_xy,uncertainty,locprec,precision)");
+        ap.refer(&mut sigma_mult)
+            .add_option(&["--sigma-mult"], Store,
+            "Multiply each point's precision by this, in column mode (default: 1)");
+        ap.refer(&mut sigma_min)
+            .add_option(&["--sigma-min"], StoreOption,
+            "Smallest sigma in pixels, in column mode");
+        ap.refer(&mut sigma_max)
+            .add_option(&["--sigma-max"], StoreOption,
+            "Largest sigma in pixels, in column mode");
+        ap.refer(&mut x_aliases)
+            .add_option(&["--x-column"], Store,
+            "Comma separated header names for the x column (default: x [nm],xnm,x_nm,x)");
+        ap.refer(&mut y_aliases)
+            .add_option(&["--y-column"], Store,
+            "Comma separated header names for the y column (default: y [nm],ynm,y_nm,y)");
+        ap.refer(&mut z_aliases)
+            .add_option(&["--z-column"], Store,
+            "Comma separated header names for the z column (default: z [nm],znm,z_nm,z)");
+        ap.refer(&mut id_aliases)
+            .add_option(&["-i", "--id-column"], Store,
+            "Comma separated header names for the model ID column. Given names must match a header. With no ID column at all, all points are one model.");
+        ap.refer(&mut weighting)
+            .add_option(&["--weighting"], Store,
+            "unit gives every point the same mass, column weights each point by a column (default: unit)");
+        ap.refer(&mut weight_aliases)
+            .add_option(&["--weight-column"], Store, &weight_help);
+        ap.refer(&mut weight_norm)
+            .add_option(&["--weight-norm"], Store,
+            "Normalise the weights in each model: none, sum, mean or max (default: none)");
+        ap.refer(&mut frame_aliases)
+            .add_option(&["--frame-column"], Store,
+            "Comma separated header names for the frame column (default: frame,frames,frame_number)");
+        ap.refer(&mut frame_start)
+            .add_option(&["--frame-start"], StoreOption,
+            "First frame to render");
+        ap.refer(&mut frame_end)
+            .add_option(&["--frame-end"], StoreOption,
+            "Last frame to render");
+        ap.refer(&mut time_blocks)
+            .add_option(&["--time-blocks"], Store,
+            "Split the frames into this many blocks and render each into its own directory (default: 1)");
+        ap.refer(&mut drift)
+            .add_option(&["--drift"], StoreTrue,
+            "Estimate the stage drift over the whole table and correct for it before rendering");
+        ap.refer(&mut drift_settings.windows)
+            .add_option(&["--drift-windows"], Store,
+            "Number of time windows to cross correlate for the drift (default: 10)");
+        ap.refer(&mut drift_settings.pixel)
+            .add_option(&["--drift-pixel"], Store,
+            "Bin size of the renders used for the drift, in CSV units (default: 20)");
+        ap.refer(&mut drift_settings.max_shift)
+            .add_option(&["--drift-max"], Store,
+            "Largest drift between windows to search for, in CSV units (default: 500)");
+        ap.refer(&mut drift_settings.degree)
+            .add_option(&["--drift-degree"], Store,
+            "Degree of the polynomial fitted to the drift (default: 3)");
+        ap.refer(&mut merge_distance)
+            .add_option(&["--merge-distance"], StoreOption,
+            "Merge repeated localisations of a blinking event that are within this distance, in CSV units");
+        ap.refer(&mut merge_gap)
+            .add_option(&["--merge-gap"], Store,
+            "Number of dark frames allowed between repeats of a blinking event (default: 1)");
+        ap.refer(&mut photon_aliases)
+            .add_option(&["--photons-column"], Store, &photon_help);
+        ap.refer(&mut width)
+            .add_option(&["--width"], Store,
+            "Width of the images in pixels (default: 1280)");
+        ap.refer(&mut height)
+            .add_option(&["--height"], StoreOption,
+            "Height of the images in pixels (default: the width)");
+        ap.refer(&mut margin)
+            .add_option(&["--margin"], Store,
+            "Fraction of the image left as a margin around the largest model (default: 0.05)");
+        ap.refer(&mut fit)
+            .add_option(&["--fit"], StoreTrue,
+            "Scale the models so each one stays inside the image at any rotation, rather than the largest spanning twice the image");
+        ap.refer(&mut seed)
+            .add_option(&["-s", "--seed"], StoreOption,
+            "Master seed for the random numbers. Without one, a seed is picked and printed");
+        ap.refer(&mut splat_error)
+            .add_option(&["--splat-error"], Store,
+            "Fraction of each Gaussian's mass we may leave out when drawing it. 0 draws every point across the whole image (default: 0.00001)");
+        ap.refer(&mut accepted_path)
+            .add_option(&["-a", "--accepted"], Store,
+            "Path to a file listing the indices of the models to render, one per line");
+        ap.refer(&mut rotation)
+            .add_option(&["-r", "--rotation"], Store,
+            "Random rotation for each image: plane, tilt or sphere (default: plane)");
+        ap.refer(&mut max_tilt)
+            .add_option(&["--max-tilt"], Store,
+            "Largest tilt out of the image plane in degrees, for tilt rotations (default: 90)");
+        ap.refer(&mut augments)
+            .add_option(&["-n", "--augments"], Store,
+            "Number of renders of each model (default: 1)");
+        ap.refer(&mut angles)
+            .add_option(&["--angles"], Store,
+            "Angles of the renders of a model: random, or even steps of the spin (default: random)");
+        ap.refer(&mut max_points)
+            .add_option(&["-m", "--max-points"], Store,
+            "Keep at most this many points of each model, drawn at random. 0 keeps them all (default: 0)");
+        ap.refer(&mut keep_fraction)
+            .add_option(&["--keep-fraction"], Store,
+            "Keep this fraction of the points of each model, drawn at random (default: 1)");
+        ap.refer(&mut draws)
+            .add_option(&["--draws"], Store,
+            "Number of independent subsamples of each model (default: 1)");
+        ap.refer(&mut jitter)
+            .add_option(&["--jitter"], StoreOption,
+            "Move every point by Gaussian noise with this sigma, in CSV units, before rendering");
+        ap.refer(&mut jitter_precision)
+            .add_option(&["--jitter-precision"], StoreTrue,
+            "Take the sigma of the jitter from each point's precision column, falling back to --jitter");
+        ap.refer(&mut mode)
+            .add_option(&["--mode"], Store,
+            "How points become pixels: gauss, hist, hist-blur or max (default: gauss)");
+        ap.refer(&mut format)
+            .add_option(&["--format"], Store,
+            "Write each image to its own file, or pack them into FITS cubes, multi extension files or NumPy stacks: files, cube, mef, npy or npz (default: files)");
+        ap.refer(&mut chunk_size)
+            .add_option(&["--chunk-size"], Store,
+            "Number of images in each packed file (default: 100)");
+        ap.refer(&mut dtype)
+            .add_option(&["--dtype"], Store,
+            "Pixel type of the npy and npz stacks: float32 or float16 (default: float32)");
+        export::add_options(&mut ap, &mut export_settings);
+        split::add_options(&mut ap, &mut split_settings);
+        ap.refer(&mut resume)
+            .add_option(&["--resume"], StoreTrue,
+            "Pick up an interrupted run, keeping the images it finished. Needs the same --seed and options");
+        ap.parse_args_or_exit();
+    }
+
+    if let Err(e) = export_settings.check().and_then(|_| split_settings.check()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    let mut aliases = ColumnAliases::new();
+    if x_aliases.len() > 0 { aliases.x = split_aliases(&x_aliases); }
+    if y_aliases.len() > 0 { aliases.y = split_aliases(&y_aliases); }
+    if z_aliases.len() > 0 { aliases.z = split_aliases(&z_aliases); }
+    if sigma_aliases.len() > 0 { aliases.sigma = split_aliases(&sigma_aliases); }
+    // The precision is only read when something uses it, so a
+    // column full of gaps does no harm otherwise
+    if sigma_mode == SigmaMode::Column || jitter_precision {
+        aliases.needed.push("sigma");
+    } else {
+        aliases.sigma = vec![];
+    }
+    if frame_aliases.len() > 0 { aliases.frame = split_aliases(&frame_aliases); }
+    if photon_aliases.len() > 0 { aliases.photons = split_aliases(&photon_aliases); }
+    if frame_start.is_some() || frame_end.is_some() || time_blocks > 1 || drift || merge_distance.is_some() {
+        aliases.needed.push("frame");
+    }
+    if let (Some(start), Some(end)) = (frame_start, frame_end) {
+        if end < start {
+            eprintln!("--frame-end {} is before --frame-start {}, so no frames are left", end, start);
+            process::exit(1);
+        }
+    }
+    let height = height.unwrap_or(width);
+    if width == 0 || height == 0 || margin < 0.0 || margin >= 1.0 {
+        eprintln!("The image size must be at least one pixel and the margin between 0 and 1");
+        process::exit(1);
+    }
+    if resume && seed.is_none() {
+        eprintln!("--resume needs the --seed of the run it picks up");
+        process::exit(1);
+    }
+    let seed = seed.unwrap_or_else(|| thread_rng().gen::<u64>());
+    println!("Seed: {}", seed);
+    if time_blocks == 0 {
+        eprintln!("The number of time blocks must be at least 1");
+        process::exit(1);
+    }
+    if weight_aliases.len() > 0 { aliases.weight = split_aliases(&weight_aliases); }
+    if weighting == Weighting::Column {
+        aliases.needed.push("weight");
+    } else {
+        aliases.weight = vec![];
+    }
+    if id_aliases.len() > 0 {
+        // Asked for by name, so it had better be there
+        aliases.id = split_aliases(&id_aliases);
+        aliases.needed.push("id");
+    }
+
+    let mut accepted : Vec<usize> = vec!();
+
+    if accepted_path.len() > 0 {
+        match read_accepted(&accepted_path) {
+            Ok(a) => { accepted = a; },
+            Err(e) => {
+                eprintln!("Error reading accepted list: {}", e);
+                process::exit(1);
+            }
+        }
+        println!("Read {} accepted indices from {}", accepted.len(), accepted_path);
+    }
+
+    match parse_csv(&csv_path, &aliases) {
+        Ok((mut models, columns)) => {
+            println!("Number of models: {}", models.len());
+            let parsed = models.len();
+            if frame_start.is_some() || frame_end.is_some() {
+                models = filter_frames(&models, frame_start, frame_end);
+                if models.len() < parsed {
+                    println!("{} models have no points in the frame window and are left out", parsed - models.len());
+                }
+                if models.is_empty() {
+                    eprintln!("No model has any points in the frame window");
+                    process::exit(1);
+                }
+            }
+            if drift {
+                let use_z = columns.z.is_some();
+                match estimate_drift(&models, &drift_settings, use_z) {
+                    Ok((curve, estimates)) => {
+                        for e in &estimates {
+                            println!("Drift at frame {} : {}, {}, {}", e.0, e.1, e.2, e.3);
+                        }
+                        apply_drift(&mut models, &curve, use_z);
+                        if let Err(e) = write_drift(&out_path, &curve, &estimates) {
+                            eprintln!("Error writing the drift curve: {}", e);
+                            process::exit(1);
+                        }
+                    },
+                    Err(e) => {
+                        eprintln!("Error estimating the drift: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+            if let Some(distance) = merge_distance {
+                let mut counts : Vec<(usize, i64, usize, usize)> = vec![];
+                for model in models.iter_mut() {
+                    let before = model.points.len();
+                    model.points = merge_blinks(&model.points, distance, merge_gap);
+                    counts.push((model.index, model.id, before, model.points.len()));
+                }
+                let before : usize = counts.iter().map(|c| c.2).sum();
+                let after : usize = counts.iter().map(|c| c.3).sum();
+                println!("Merged blinking events: {} points down to {}", before, after);
+                if let Err(e) = write_merged(&out_path, &counts) {
+                    eprintln!("Error writing the merge counts: {}", e);
+                    process::exit(1);
+                }
+            }
+            if models.is_empty() {
+                eprintln!("No models to render in {}", csv_path);
+                process::exit(1);
+            }
+            let (_mean, median, sd, _min, _max) = find_stats(&models);
+            let cutoff = median.saturating_sub((2.0 * sd) as u32);
+            let mut accepted_models = filter_models(&models, cutoff, accepted.clone());
+
+            // Say why each accepted model we are not rendering was left out
+            let kept : Vec<usize> = accepted_models.iter().map(|m| m.index).collect();
+            let missing : Vec<&usize> = accepted.iter().filter(|idx| !kept.contains(idx)).collect();
+            for idx in missing.iter() {
+                match models.iter().find(|m| m.index == **idx) {
+                    Some(m) => println!("Accepted model {} is left out: it has {} points, below the cutoff of {}",
+                        idx, m.points.len(), cutoff),
+                    None if **idx < parsed => println!("Accepted model {} is left out: it has no points in the frame window", idx),
+                    None => println!("Accepted model {} is left out: no model has that index", idx)
+                }
+            }
+            if missing.len() > 0 {
+                println!("{} of {} accepted models are left out", missing.len(), accepted.len());
+            }
+
+            if accepted_models.len() == 0 {
+                eprintln!("No models left after filtering with cutoff {}", cutoff);
+                process::exit(1);
+            }
+
+            // Whole models go to a split, so their renders stay together. They
+            // are keyed by ID, which stays put when pores come and go from the CSV.
+            if split_settings.enabled() {
+                let keys : Vec<i64> = accepted_models.iter().map(|m| m.id).collect();
+                let splits = match split::assign(&keys, &split_settings, split_settings.seed.unwrap_or(seed)) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        eprintln!("Error splitting the models: {}", e);
+                        process::exit(1);
+                    }
+                };
+                for model in accepted_models.iter_mut() {
+                    model.split = splits.get(&model.id).cloned();
+                }
+                for name in split::split_names(splits.values().cloned().collect()) {
+                    println!("Split {} : {} models", name, splits.values().filter(|s| **s == name).count());
+                }
+                if let Err(e) = split::write_splits(&splits, "model_id", &Path::new(&out_path).join("splits.csv")) {
+                    eprintln!("Error writing the splits: {}", e);
+                    process::exit(1);
+                }
+            }
 
-   
-    match parse_csv(&args[1]) {
-        Ok(mut models) => {
-            let (w, h) = find_extents(&models);
-            let (mean, median, sd, min, max) = find_stats(&models);
-            let cutoff = median - ((2.0 * sd) as u32);
-            let accepted_models = filter_models(&models, cutoff, accepted);
             // Find extents a second time
-            let (w, h) = find_extents(&accepted_models);
+            let (w, h, d) = find_extents(&accepted_models);
             let (mean, median, sd, min, max) = find_stats(&accepted_models);
-            let cutoff = median - ((2.0 * sd) as u32);
             println!("Model sizes (min, max, mean, median, sd) : {}, {}, {}, {}, {}", 
                 min, max, mean, median, sd);
-            let mut scale = 2.0 / w;
-            if h > w { scale = 2.0 / h; }
-            println!("Max Width / Height: {}, {}", w, h);
-            println!("Scale / Scalar: {}, {}", scale, scale * (WIDTH as f32) * SHRINK); 
-            render(&accepted_models, &args[2], nthreads, sigma, scale, max_points);
+            // Once we rotate out of the plane, the depth can end up across the image
+            let mut extent = w.max(h);
+            if rotation != RotationMode::Plane { extent = extent.max(d); }
+            let mut scale = 2.0 / extent;
+            println!("Max Width / Height / Depth: {}, {}, {}", w, h, d);
+            if fit {
+                // The radius spans half the shorter side, whatever the rotation
+                let radius = find_radius(&accepted_models, rotation != RotationMode::Plane);
+                println!("Max Radius: {}", radius);
+                if radius > 0.0 { scale = 0.5 / radius; }
+            }
+
+            let settings = RenderSettings {
+                sigma : sigma,
+                sigma_mode : sigma_mode,
+                sigma_mult : sigma_mult,
+                sigma_min : sigma_min,
+                sigma_max : sigma_max,
+                weight_name : match columns.weight {
+                    Some(idx) => columns.headers[idx].to_string(),
+                    None => String::from("UNIT")
+                },
+                weight_norm : weight_norm,
+                scale : scale,
+                max_points : max_points,
+                keep_fraction : keep_fraction,
+                draws : draws.max(1),
+                rotation : rotation,
+                max_tilt : max_tilt.to_radians(),
+                augments : augments.max(1),
+                angles : angles,
+                jitter_mode : if jitter_precision {
+                    JitterMode::Precision
+                } else if jitter.is_some() {
+                    JitterMode::Fixed
+                } else {
+                    JitterMode::None
+                },
+                jitter : jitter.unwrap_or(0.0),
+                mode : mode,
+                format : format,
+                dtype : dtype,
+                chunk_size : chunk_size.max(1),
+                export : export_settings,
+                splat_error : splat_error,
+                width : width,
+                height : height,
+                shrink : 1.0 - margin,
+                seed : seed,
+                source : csv_path.clone(),
+                resume : resume
+            };
+            println!("Image size: {} x {}", width, height);
+            println!("Scale / Scalar: {}, {}", scale, settings.scalar());
+            let mut rows : Vec<ManifestRow> = vec![];
+            if time_blocks > 1 {
+                let blocks = split_blocks(&accepted_models, time_blocks);
+                for (b, block) in blocks.iter().enumerate() {
+                    let block_path = Path::new(&out_path).join(format!("block_{:02}", b));
+                    if let Err(e) = fs::create_dir_all(&block_path) {
+                        eprintln!("Error creating {}: {}", block_path.display(), e);
+                        process::exit(1);
+                    }
+                    let block_rows = match render_splits(block, &block_path.to_string_lossy().to_string(), nthreads,
+                        &settings, split_settings.layout) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            process::exit(1);
+                        }
+                    };
+                    for mut row in block_rows {
+                        row.file = format!("block_{:02}/{}", b, row.file);
+                        row.block = Some(b);
+                        rows.push(row);
+                    }
+                }
+            } else {
+                rows = match render_splits(&accepted_models, &out_path, nthreads, &settings, split_settings.layout) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                };
+            }
+
+            if let Err(e) = write_manifest(&rows, &out_path) {
+                eprintln!("Error writing the manifest: {}", e);
+                process::exit(1);
+            }
+
+            if let Err(e) = write_rendered(&accepted_models, &rows, &out_path) {
+                eprintln!("Error writing the rendered list: {}", e);
+                process::exit(1);
+            }
         }, 
         Err(e) => {
-            println!("Error parsing MATLAB File: {}", e);
+            eprintln!("Error parsing CSV file: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fitrs::FitsData;
+
+    fn header(names : &[&str]) -> csv::StringRecord {
+        csv::StringRecord::from(names.to_vec())
+    }
+
+    #[test]
+    fn columns_are_found_by_each_alias() {
+        let aliases = ColumnAliases::new();
+        let lists = vec![&aliases.x, &aliases.y, &aliases.z, &aliases.id, &aliases.sigma,
+            &aliases.weight, &aliases.photons, &aliases.frame];
+        for list in lists {
+            for alias in list {
+                // Case and surrounding space do not matter
+                let headers = header(&["other", &format!(" {} ", alias.to_uppercase())]);
+                assert_eq!(find_column(&headers, list), Some(1), "alias '{}'", alias);
+            }
+        }
+        // Earlier aliases win over later ones, wherever they sit
+        let headers = header(&["x", "y", "x [nm]", "y [nm]"]);
+        assert_eq!(find_column(&headers, &aliases.x), Some(2));
+        let columns = map_columns(&headers, &aliases).unwrap();
+        assert_eq!((columns.x, columns.y, columns.z, columns.id), (2, 3, None, None));
+    }
+
+    #[test]
+    fn a_missing_column_is_named() {
+        let aliases = ColumnAliases::new();
+        let err = map_columns(&header(&["y", "id"]), &aliases).err().unwrap().to_string();
+        assert_eq!(err, "No x column found. Looked for: x [nm], xnm, x_nm, x. Header was: y, id");
+
+        // A column asked for by name has to be there
+        let mut aliases = ColumnAliases::new();
+        aliases.id = split_aliases("pore");
+        aliases.needed.push("id");
+        let err = map_columns(&header(&["x", "y", "id"]), &aliases).err().unwrap().to_string();
+        assert_eq!(err, "No id column found. Looked for: pore. Header was: x, y, id");
+        let columns = map_columns(&header(&["x", "y", "Pore"]), &aliases).unwrap();
+        assert_eq!(columns.id, Some(2));
+    }
+
+    #[test]
+    fn random_rotations_are_proper_and_keep_to_the_tilt() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let max_tilt = 20.0f32.to_radians();
+        for &mode in &[RotationMode::Plane, RotationMode::Tilt, RotationMode::Sphere] {
+            for _ in 0..500 {
+                let r = random_rotation(&mut rng, mode, max_tilt);
+                assert!((r.matrix().determinant() - 1.0).abs() < 1e-4);
+                assert!((r.matrix() * r.matrix().transpose() - na::Matrix3::identity()).iter().all(|d| d.abs() < 1e-4));
+                let q = UnitQuaternion::from_rotation_matrix(&r);
+                assert!((q.into_inner().norm() - 1.0).abs() < 1e-5);
+
+                // How far the model's z axis leans away from the view
+                let up = (r * Vector3::z()).z;
+                match mode {
+                    RotationMode::Plane => assert!((up - 1.0).abs() < 1e-5),
+                    RotationMode::Tilt => assert!(up >= max_tilt.cos() - 1e-5, "tilted {} degrees", up.acos().to_degrees()),
+                    RotationMode::Sphere => {}
+                }
+            }
+        }
+        // Sphere rotations do turn models over
+        let flipped = (0..500).filter(|_| (random_rotation(&mut rng, RotationMode::Sphere, max_tilt) * Vector3::z()).z < 0.0).count();
+        assert!(flipped > 150 && flipped < 350);
+    }
+
+    #[test]
+    fn a_blank_precision_is_read_as_missing() {
+        let path = std::env::temp_dir().join(format!("precision_test_{}.csv", std::process::id()));
+        fs::write(&path, "x [nm],y [nm],uncertainty_xy [nm],id\n1,2,8.5,1\n3,4,,1\n5,6,  ,1\n").unwrap();
+        let mut aliases = ColumnAliases::new();
+        aliases.needed.push("sigma");
+        let parsed = parse_csv(&path.to_string_lossy().to_string(), &aliases);
+        fs::remove_file(&path).unwrap();
+        let (models, _) = parsed.unwrap();
+        let sigmas : Vec<f32> = models[0].points.iter().map(|p| p.sigma).collect();
+        // No precision means the global sigma, in point_sigma and jitter_points
+        assert_eq!(sigmas, vec![8.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn the_frame_window_drops_emptied_models() {
+        let point = |frame : u32| Point { x : 0.0, y : 0.0, z : 0.0, sigma : 0.0, weight : 1.0, photons : 1.0, frame : frame };
+        let models = vec![
+            Model { index : 0, id : 7, points : vec![point(1), point(5), point(9)], centre : None, split : None },
+            Model { index : 1, id : 8, points : vec![point(20)], centre : None, split : None }];
+        let windowed = filter_frames(&models, Some(4), Some(10));
+        assert_eq!(windowed.len(), 1);
+        assert_eq!((windowed[0].index, windowed[0].points.len()), (0, 2));
+        assert!(filter_frames(&models, Some(30), None).is_empty());
+        // Nothing left to take statistics of is not an error
+        assert!(find_stats(&vec![]) == (0.0, 0, 0.0, 0, 0));
+    }
+
+    fn point_at(x : f32, y : f32, frame : u32) -> Point {
+        Point { x : x, y : y, z : 0.0, sigma : 0.0, weight : 1.0, photons : 1.0, frame : frame }
+    }
+
+    #[test]
+    fn drift_follows_a_planted_line() {
+        // Fixed emitters that blink on and off, on a stage drifting
+        // 0.1 nm per frame in x and -0.05 in y. No one blinks between
+        // frames 300 and 399, so one window is empty.
+        let mut rng = StdRng::seed_from_u64(7);
+        let emitters : Vec<(f32, f32)> = (0..200).map(|_|
+            (rng.gen_range(0.0, 2000.0), rng.gen_range(0.0, 2000.0))).collect();
+        let noise = Normal::new(0.0, 4.0).unwrap();
+        let mut points = vec![];
+        for frame in (0..1000).filter(|f| *f < 300 || *f >= 400) {
+            for _ in 0..5 {
+                let (ex, ey) = emitters[rng.gen_range(0, emitters.len())];
+                points.push(point_at(ex + 0.1 * frame as f32 + noise.sample(&mut rng),
+                    ey - 0.05 * frame as f32 + noise.sample(&mut rng), frame));
+            }
+        }
+        let models = vec![Model { index : 0, id : 0, points : points, centre : None, split : None }];
+        let settings = DriftSettings { windows : 10, pixel : 10.0, max_shift : 100.0, degree : 1 };
+
+        let (curve, estimates) = estimate_drift(&models, &settings, false).unwrap();
+        assert_eq!(estimates.len(), 9);
+        // The drift is measured from the first window, centred on frame 50
+        for frame in &[0u32, 250, 500, 999] {
+            let (dx, dy, _) = curve.at(*frame);
+            let (ex, ey) = (0.1 * (*frame as f32 - 50.0), -0.05 * (*frame as f32 - 50.0));
+            assert!((dx - ex).abs() < 3.0 && (dy - ey).abs() < 3.0,
+                "frame {}: drift {}, {} not {}, {}", frame, dx, dy, ex, ey);
+        }
+    }
+
+    fn blink(x : f32, y : f32, photons : f32, frame : u32) -> Point {
+        Point { x : x, y : y, z : 0.0, sigma : 0.0, weight : 1.0, photons : photons, frame : frame }
+    }
+
+    #[test]
+    fn blinks_merge_to_a_photon_weighted_mean() {
+        let points = vec![blink(100.0, 200.0, 300.0, 5), blink(104.0, 196.0, 100.0, 6)];
+        let merged = merge_blinks(&points, 10.0, 1);
+        assert_eq!(merged.len(), 1);
+        assert!((merged[0].x - 101.0).abs() < 1e-4 && (merged[0].y - 199.0).abs() < 1e-4);
+        assert_eq!(merged[0].photons, 400.0);
+        assert_eq!(merged[0].weight, 2.0);
+        assert_eq!(merged[0].frame, 5);
+    }
+
+    #[test]
+    fn blinks_stay_apart_beyond_the_distance_or_gap() {
+        // Too far apart, in the same frame, and back after three dark frames
+        let far = vec![blink(0.0, 0.0, 1.0, 1), blink(20.0, 0.0, 1.0, 2)];
+        assert_eq!(merge_blinks(&far, 10.0, 1).len(), 2);
+        let same_frame = vec![blink(0.0, 0.0, 1.0, 1), blink(1.0, 0.0, 1.0, 1)];
+        assert_eq!(merge_blinks(&same_frame, 10.0, 1).len(), 2);
+        let late = vec![blink(0.0, 0.0, 1.0, 1), blink(1.0, 0.0, 1.0, 5)];
+        assert_eq!(merge_blinks(&late, 10.0, 2).len(), 2);
+        assert_eq!(merge_blinks(&late, 10.0, 3).len(), 1);
+        assert_eq!(merge_blinks(&late, 10.0, u32::max_value()).len(), 1);
+    }
+
+    #[test]
+    fn blinks_chain_into_one_event() {
+        // Each step is within the distance of the running centre, though
+        // the ends are not, and each gap is allowed on its own
+        let chain : Vec<Point> = (0..6).map(|i| blink(2.0 * i as f32, 0.0, 1.0, 2 * i)).collect();
+        let merged = merge_blinks(&chain, 6.5, 1);
+        assert_eq!(merged.len(), 1);
+        assert!((merged[0].x - 5.0).abs() < 1e-4);
+        assert_eq!(merged[0].weight, 6.0);
+        // Two events side by side keep their own points
+        let mut pair = chain.clone();
+        pair.extend((0..6).map(|i| blink(500.0 + 2.0 * i as f32, 0.0, 1.0, 2 * i)));
+        assert_eq!(merge_blinks(&pair, 6.5, 1).len(), 2);
+    }
+
+    const WIDTH : u32 = 320;
+    const HEIGHT : u32 = 200;
+
+    /// The original renderer - every point evaluated at every pixel.
+    fn render_exhaustive(projected : &Vec<(f32, f32, f32, f32)>) -> Vec<Vec<f32>> {
+        let pi = std::f32::consts::PI;
+        let mut timg : Vec<Vec<f32>> = vec![vec![0.0; HEIGHT as usize]; WIDTH as usize];
+
+        for ex in 0..WIDTH {
+            for ey in 0..HEIGHT {
+                for &(xs, ys, sigma, weight) in projected {
+                    let xf = xs + (WIDTH as f32/ 2.0);
+                    let yf = ys + (HEIGHT as f32 / 2.0);
+                    if xf >= 0.0 && xf < WIDTH as f32 && yf >= 0.0 && yf < HEIGHT as f32 {
+                        let pval = weight * (1.0 / (2.0 * pi * sigma.powf(2.0))) *
+                            (-((ex as f32 - xf).powf(2.0) + (ey as f32 - yf).powf(2.0)) / (2.0*sigma.powf(2.0))).exp();
+                        timg[ex as usize][ey as usize] += pval;
+                    }
+                }
+            }
+        }
+        timg
+    }
+
+    fn test_points() -> Vec<(f32, f32, f32, f32)> {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut projected = vec![];
+        for _ in 0..24 {
+            projected.push((rng.gen_range(-180.0, 180.0), rng.gen_range(-120.0, 120.0),
+                rng.gen_range(0.8, 6.0), rng.gen_range(0.5, 2.0)));
+        }
+        // Right on the edges of the image
+        projected.push((-(WIDTH as f32) / 2.0, 0.0, 2.0, 1.0));
+        projected.push((0.0, (HEIGHT as f32) / 2.0 - 0.01, 2.0, 1.0));
+        projected
+    }
+
+    #[test]
+    fn splat_matches_exhaustive_render() {
+        let projected = test_points();
+        let reference = render_exhaustive(&projected);
+        let pi = std::f32::consts::PI;
+
+        for &splat_error in &[1e-3f32, 1e-5] {
+            let splatted = splat_points(&projected, WIDTH, HEIGHT, splat_error, false);
+            // No pixel can be off by more than every point's cut off tail
+            let bound : f32 = projected.iter().map(|p| p.3 * splat_error / (2.0 * pi * p.2 * p.2)).sum();
+
+            for x in 0..WIDTH as usize {
+                for y in 0..HEIGHT as usize {
+                    let diff = (splatted[x][y] - reference[x][y]).abs();
+                    assert!(diff <= bound + 1e-6,
+                        "pixel {}, {} off by {} with error {}", x, y, diff, splat_error);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn splat_without_truncation_is_exact() {
+        let projected = test_points();
+        assert!(splat_points(&projected, WIDTH, HEIGHT, 0.0, false) == render_exhaustive(&projected));
+    }
+
+    #[test]
+    fn blurred_histogram_keeps_the_mass() {
+        // Well inside the image, so nothing is blurred off the edge
+        let projected : Vec<(f32, f32, f32, f32)> = test_points().into_iter()
+            .filter(|p| p.0.abs() < 140.0 && p.1.abs() < 80.0).collect();
+        let binned = bin_points(&projected, WIDTH, HEIGHT);
+        let blurred = blur_image(&binned, 3.0, 1e-5);
+        let mass : f32 = projected.iter().map(|p| p.3).sum();
+        let binned_mass : f32 = binned.iter().map(|c| c.iter().sum::<f32>()).sum();
+        let blurred_mass : f32 = blurred.iter().map(|c| c.iter().sum::<f32>()).sum();
+        assert!((binned_mass - mass).abs() < 1e-4);
+        assert!((blurred_mass - mass).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fitted_models_stay_inside_at_any_rotation() {
+        // A long thin model, corners and all, off the origin
+        let point = |x : f32, y : f32, z : f32| Point { x : x, y : y, z : z, sigma : 0.0, weight : 1.0, photons : 1.0, frame : 0 };
+        let points = vec![point(100.0, 50.0, -5.0), point(300.0, 50.0, 5.0), point(100.0, 70.0, 5.0), point(300.0, 70.0, -5.0)];
+        let models = vec![Model { index : 0, id : 0, points : points.clone(), centre : None, split : None }];
+        let radius = find_radius(&models, true);
+        assert!((radius - (100.0f32 * 100.0 + 10.0 * 10.0 + 5.0 * 5.0).sqrt()).abs() < 1e-3);
+        assert!(find_radius(&models, false) < radius);
+
+        let width = 64;
+        let height = 48;
+        let scalar = 0.5 / radius * height as f32;
+        let centre = find_centre(&points);
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..200 {
+            let r = random_rotation(&mut rng, RotationMode::Sphere, 0.0);
+            let projected : Vec<(f32, f32, f32, f32)> = points.iter().map(|p| {
+                let rp = r * Vector3::new(p.x - centre.0, p.y - centre.1, p.z - centre.2) * scalar;
+                (rp.x, rp.y, 1.0, 1.0)
+            }).collect();
+            assert_eq!(outside_image(&projected, width, height), 0);
+        }
+        // Points past the edges are counted
+        assert_eq!(outside_image(&vec![(40.0, 0.0, 1.0, 1.0), (0.0, -24.5, 1.0, 1.0), (0.0, 0.0, 1.0, 1.0)], width, height), 2);
+    }
+
+    #[test]
+    fn rotation_angle_keeps_its_sign() {
+        for &degrees in &[-30.0f32, 30.0, 0.0, 179.0, -179.0] {
+            let r = Rotation3::from_axis_angle(&Vector3::z_axis(), degrees.to_radians());
+            let (angle, axis) = signed_rotation(&r);
+            assert!((angle - degrees).abs() < 1e-3, "{} came back as {}", degrees, angle);
+            assert!((axis.2 - 1.0).abs() < 1e-5);
+        }
+        // Flipping the axis of a spin about -z leaves no -0 behind
+        let r = Rotation3::from_axis_angle(&-Vector3::z_axis(), 0.5);
+        let (angle, axis) = signed_rotation(&r);
+        assert!((angle + 0.5f32.to_degrees()).abs() < 1e-3);
+        assert_eq!(format!("{},{},{}", axis.0, axis.1, axis.2), "0,0,1");
+        // Any rotation comes back from its angle and axis
+        let axis = Unit::new_normalize(Vector3::new(0.3, -0.5, -0.8));
+        let r = Rotation3::from_axis_angle(&axis, 1.2);
+        let (angle, (x, y, z)) = signed_rotation(&r);
+        assert!(z >= 0.0);
+        let back = Rotation3::from_axis_angle(&Unit::new_normalize(Vector3::new(x, y, z)), angle.to_radians());
+        assert!((back.matrix() - r.matrix()).iter().all(|d| d.abs() < 1e-5));
+    }
+
+    fn test_settings() -> RenderSettings {
+        RenderSettings {
+            sigma : 1.5, sigma_mode : SigmaMode::Global, sigma_mult : 1.0, sigma_min : None, sigma_max : None,
+            weight_name : String::from("UNIT"), weight_norm : WeightNorm::None, scale : 0.01,
+            max_points : 0, keep_fraction : 1.0, draws : 1, rotation : RotationMode::Plane,
+            max_tilt : 0.0, augments : 1, angles : AngleMode::Random, jitter_mode : JitterMode::None,
+            jitter : 0.0, mode : RenderMode::Gauss, format : OutputFormat::Files, chunk_size : 1,
+            dtype : Dtype::Float32, export : ExportSettings::new(), splat_error : 1e-5, width : 32, height : 32,
+            shrink : 0.95, seed : 12, source : String::from("test.csv"), resume : false
+        }
+    }
+
+    fn test_model(index : usize, id : i64, npoints : usize) -> Model {
+        let mut rng = StdRng::seed_from_u64(id as u64);
+        let points = (0..npoints).map(|_| Point {
+            x : rng.gen_range(0.0, 100.0), y : rng.gen_range(0.0, 100.0), z : rng.gen_range(-10.0, 10.0),
+            sigma : 0.0, weight : 1.0, photons : 1.0, frame : 0
+        }).collect();
+        Model { index : index, id : id, points : points, centre : None, split : None }
+    }
+
+    /// Render each model in turn, as a render thread would, and keep
+    /// the images by model index.
+    fn render_images(models : &Vec<Model>, settings : &RenderSettings) -> HashMap<usize, Vec<Vec<Vec<f32>>>> {
+        let (tx, rx) = sync_channel(models.len() * settings.renders() as usize);
+        let cancel = AtomicBool::new(false);
+        let outside = AtomicUsize::new(0);
+        for (position, model) in models.iter().enumerate() {
+            assert!(render_model(model, position, settings, &HashMap::new(), &cancel, &outside, &tx));
+        }
+        drop(tx);
+        let mut images : HashMap<usize, Vec<Vec<Vec<f32>>>> = HashMap::new();
+        for rendered in rx.iter() {
+            images.entry(rendered.row.info.index).or_insert(vec![]).push(rendered.image.unwrap());
+        }
+        images
+    }
+
+    #[test]
+    fn a_model_renders_the_same_alone_or_in_a_batch() {
+        // The seed of a model only depends on the master seed and its index
+        assert_eq!(model_seed(12, 3), model_seed(12, 3));
+        assert!(model_seed(12, 3) != model_seed(12, 4) && model_seed(12, 3) != model_seed(13, 3));
+        // Changing the mixing would change every seeded run, so it is pinned
+        assert_eq!(model_seed(0, 0), 16294208416658607535);
+
+        let mut settings = test_settings();
+        settings.rotation = RotationMode::Sphere;
+        settings.draws = 2;
+        settings.augments = 3;
+        settings.keep_fraction = 0.5;
+        settings.jitter_mode = JitterMode::Fixed;
+        settings.jitter = 2.0;
+        let alone = render_images(&vec![test_model(5, 50, 40)], &settings);
+        let batch = render_images(&vec![test_model(2, 20, 60), test_model(5, 50, 40), test_model(9, 90, 30)], &settings);
+        let reversed = render_images(&vec![test_model(9, 90, 30), test_model(5, 50, 40)], &settings);
+        assert_eq!(alone[&5].len(), 6);
+        assert!(alone[&5] == batch[&5]);
+        assert!(alone[&5] == reversed[&5]);
+        assert!(batch[&9] == reversed[&9]);
+        assert!(alone[&5][0] != alone[&5][1]);
+    }
+
+    #[test]
+    fn points_are_dropped_without_replacement() {
+        // Each point is marked by its frame
+        let points : Vec<Point> = (0..100).map(|i| Point {
+            x : i as f32, y : 0.0, z : 0.0, sigma : 0.0, weight : 1.0, photons : 1.0, frame : i }).collect();
+        let mut settings = test_settings();
+        settings.keep_fraction = 0.25;
+        assert_eq!(settings.keep_count(points.len()), 25);
+        settings.max_points = 10;
+        assert_eq!(settings.keep_count(points.len()), 10);
+        settings.max_points = 0;
+        settings.keep_fraction = 0.333;
+        assert_eq!(settings.keep_count(points.len()), 33);
+
+        let mut rng = StdRng::seed_from_u64(16);
+        let mut picked = vec![0; points.len()];
+        for _ in 0..2000 {
+            let kept = drop_points(&mut rng, &points, 25);
+            assert_eq!(kept.len(), 25);
+            // No point twice, and they keep their order
+            assert!(kept.windows(2).all(|w| w[0].frame < w[1].frame));
+            for p in kept { picked[p.frame as usize] += 1; }
+        }
+        // Every point is as likely as any other to stay, 500 times out of 2000
+        assert!(picked.iter().all(|&n| n > 400 && n < 600), "{:?}", picked);
+        assert_eq!(drop_points(&mut rng, &points, 200).len(), 100);
+    }
+
+    #[test]
+    fn precision_jitter_follows_each_point() {
+        let mut settings = test_settings();
+        settings.jitter_mode = JitterMode::Precision;
+        let scalar = settings.scalar();
+        // Without a precision a point takes the global jitter, 1 pixel here
+        settings.jitter = 1.0 / scalar;
+        let point = |sigma : f32| Point { x : 0.0, y : 0.0, z : 0.0, sigma : sigma, weight : 1.0, photons : 1.0, frame : 0 };
+        let mut rng = StdRng::seed_from_u64(17);
+
+        for &(sigma, expected) in &[(0.0f32, 1.0f32), (2.0, 2.0), (6.0, 6.0)] {
+            let points = vec![point(sigma); 4000];
+            let (jittered, mean_sd) = jitter_points(&mut rng, &points, &settings);
+            assert!((mean_sd - expected / scalar).abs() < 1e-3 * expected / scalar);
+            for axis in 0..3 {
+                let values : Vec<f32> = jittered.iter().map(|p| [p.x, p.y, p.z][axis]).collect();
+                let sd = (values.iter().map(|v| v * v).sum::<f32>() / values.len() as f32).sqrt();
+                assert!((sd / expected - 1.0).abs() < 0.05, "sigma {} moved points by {}", sigma, sd);
+            }
+        }
+        // Fixed jitter ignores the precision
+        settings.jitter_mode = JitterMode::Fixed;
+        let (jittered, mean_sd) = jitter_points(&mut rng, &vec![point(6.0); 4000], &settings);
+        let sd = (jittered.iter().map(|p| p.x * p.x).sum::<f32>() / 4000.0).sqrt();
+        assert!((mean_sd - settings.jitter).abs() < 1e-3 && (sd - 1.0).abs() < 0.05);
+    }
+
+    /// Split a FITS file into its HDUs - the header cards as key and
+    /// value, and the bytes of the data - checking the 2880 byte blocks.
+    fn read_hdus(bytes : &[u8]) -> Vec<(Vec<(String, String)>, Vec<u8>)> {
+        assert_eq!(bytes.len() % 2880, 0);
+        let mut hdus = vec![];
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let mut cards : Vec<(String, String)> = vec![];
+            loop {
+                let card = String::from_utf8(bytes[pos..pos + 80].to_vec()).unwrap();
+                pos += 80;
+                if card.trim_end() == "END" { break; }
+                if &card[8..10] == "= " {
+                    let value = card[10..].trim_start();
+                    let value = if value.starts_with('\'') {
+                        value[1..value[1..].find('\'').unwrap() + 1].trim_end()
+                    } else {
+                        value.split('/').next().unwrap().trim()
+                    };
+                    cards.push((card[..8].trim_end().to_string(), value.to_string()));
+                }
+            }
+            pos += (2880 - pos % 2880) % 2880;
+            let value = |key : &str| cards.iter().find(|c| c.0 == key).map(|c| c.1.parse::<i64>().unwrap());
+            let naxis = value("NAXIS").unwrap();
+            let mut len = if naxis == 0 { 0 } else { 1 };
+            for n in 1..(naxis + 1) { len *= value(&format!("NAXIS{}", n)).unwrap() as usize; }
+            len *= value("BITPIX").unwrap().abs() as usize / 8;
+            hdus.push((cards, bytes[pos..pos + len].to_vec()));
+            // The data is padded out to a whole block too
+            pos += (len + 2879) / 2880 * 2880;
         }
+        assert_eq!(pos, bytes.len());
+        hdus
+    }
+
+    fn card<'a>(cards : &'a Vec<(String, String)>, key : &str) -> &'a str {
+        &cards.iter().find(|c| c.0 == key).unwrap().1
+    }
+
+    fn test_row(file : &str, plane : Option<usize>, nonzero : usize) -> ManifestRow {
+        let info = ImageInfo {
+            index : 0, id : 0, seed : 0, draw : 0, augment : 0, jitter : 0.0,
+            rotation : Rotation3::identity(), centre : (0.0, 0.0, 0.0), npoints : 0
+        };
+        ManifestRow {
+            file : String::from(file), plane : plane, block : None, split : None, info : info,
+            sigma : 1.0, total : 12.5, peak : 0.125, mean : 0.003, sd : 1e-7, nonzero : nonzero
+        }
+    }
+
+    fn chunk_planes(settings : &RenderSettings) -> Vec<(ManifestRow, Vec<Vec<f32>>)> {
+        (0..2).map(|p| {
+            let mut row = test_row("images_0000.fits", Some(p), 10 + p);
+            row.info.index = 40 + p;
+            row.info.id = -3;
+            row.info.rotation = Rotation3::from_axis_angle(&Vector3::z_axis(), 0.25 * (p + 1) as f32);
+            row.split = if p == 0 { Some(String::from("train")) } else { None };
+            let img : Vec<Vec<f32>> = (0..settings.width).map(|x| (0..settings.height).map(|y| {
+                (p * 100) as f32 + x as f32 * 10.0 + y as f32 + 0.5 }).collect()).collect();
+            (row, img)
+        }).collect()
+    }
+
+    #[test]
+    fn fits_cards_are_80_characters() {
+        assert_eq!(fits_real(1.0), "1.0E+00");
+        assert_eq!(fits_real(-2.5e-7), "-2.5E-07");
+        assert_eq!(fits_real(123456.0), "1.23456E+05");
+        assert_eq!(fits_card("NAXIS1", "64", false), format!("{:<80}", format!("NAXIS1  = {:>20}", 64)));
+        assert_eq!(fits_card("SPLIT", "it's", true), format!("{:<80}", "SPLIT   = 'it''s   '"));
+        assert_eq!(header_card("FLAG", &HeaderValue::Logical(true)).len(), 80);
+    }
+
+    #[test]
+    fn mef_chunk_reads_back() {
+        let mut settings = test_settings();
+        settings.width = 6;
+        settings.height = 4;
+        settings.format = OutputFormat::Mef;
+        let planes = chunk_planes(&settings);
+        let path = std::env::temp_dir().join(format!("mef_test_{}.fits", std::process::id())).to_string_lossy().to_string();
+        save_chunk(&planes, &path, &settings).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let hdus = read_hdus(&bytes);
+        assert_eq!(hdus.len(), 4);
+
+        // One image extension per plane, with that image's keywords
+        for (p, (row, img)) in planes.iter().enumerate() {
+            let cards = &hdus[p + 1].0;
+            assert_eq!(card(cards, "XTENSION"), "IMAGE");
+            assert_eq!((card(cards, "NAXIS"), card(cards, "NAXIS1"), card(cards, "NAXIS2")), ("2", "6", "4"));
+            assert_eq!(card(cards, "MODELIDX"), (40 + p).to_string());
+            let (angle, _) = signed_rotation(&row.info.rotation);
+            assert!((card(cards, "ROTANGLE").parse::<f32>().unwrap() - angle).abs() < 1e-4);
+            let pixels : Vec<f32> = hdus[p + 1].1.chunks(4).map(|b| f32::from_be_bytes([b[0], b[1], b[2], b[3]])).collect();
+            assert!(pixels == fits_plane(img, &settings));
+        }
+
+        // The table lays its rows out as its TFORMs say
+        let (cards, data) = &hdus[3];
+        let fields = planes[0].0.fields();
+        assert_eq!(card(cards, "XTENSION"), "BINTABLE");
+        assert_eq!(card(cards, "TFIELDS"), fields.len().to_string());
+        assert_eq!(card(cards, "NAXIS2"), "2");
+        let forms : Vec<&str> = (0..fields.len()).map(|c| card(cards, &format!("TFORM{}", c + 1))).collect();
+        let widths : Vec<usize> = forms.iter().map(|f| match *f {
+            "K" => 8,
+            "E" => 4,
+            f => f.trim_end_matches('A').parse().unwrap()
+        }).collect();
+        let row_bytes : usize = widths.iter().sum();
+        assert_eq!(card(cards, "NAXIS1"), row_bytes.to_string());
+        assert_eq!(data.len(), 2 * row_bytes);
+
+        // And the first row decodes to what we wrote
+        let mut pos = 0;
+        for (c, (name, value)) in fields.iter().enumerate() {
+            assert_eq!(card(cards, &format!("TTYPE{}", c + 1)), *name);
+            let cell = &data[pos..pos + widths[c]];
+            pos += widths[c];
+            match value {
+                ManifestValue::Int(n) => {
+                    let mut b = [0u8; 8];
+                    b.copy_from_slice(cell);
+                    assert_eq!(i64::from_be_bytes(b), *n, "{}", name);
+                },
+                ManifestValue::Real(r) => assert_eq!(f32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]), *r, "{}", name),
+                ManifestValue::Text(t) => assert_eq!(String::from_utf8(cell.to_vec()).unwrap().trim_end(), t),
+                // Missing numbers are 0 or NaN, missing text is blank
+                ManifestValue::Missing => match forms[c] {
+                    "K" => assert!(cell.iter().all(|b| *b == 0)),
+                    "E" => assert!(f32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]).is_nan()),
+                    _ => assert!(cell.iter().all(|b| *b == b' '), "{}", name)
+                }
+            }
+        }
+
+        // fitrs reads the extensions we wrote ourselves
+        let fits = Fits::open(&path).unwrap();
+        for (p, (_, img)) in planes.iter().enumerate() {
+            match fits.get(p + 1).unwrap().read_data() {
+                FitsData::FloatingPoint32(array) => {
+                    assert_eq!(array.shape, vec![6, 4]);
+                    assert!(array.data == fits_plane(img, &settings));
+                },
+                _ => panic!("extension {} is not 32 bit floats", p + 1)
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cube_chunk_reads_back() {
+        let mut settings = test_settings();
+        settings.width = 6;
+        settings.height = 4;
+        settings.format = OutputFormat::Cube;
+        let planes = chunk_planes(&settings);
+        let path = std::env::temp_dir().join(format!("cube_test_{}.fits", std::process::id())).to_string_lossy().to_string();
+        save_chunk(&planes, &path, &settings).unwrap();
+        let hdus = read_hdus(&fs::read(&path).unwrap());
+        assert_eq!(hdus.len(), 2);
+        assert_eq!(card(&hdus[0].0, "NAXIS3"), "2");
+        assert_eq!(card(&hdus[1].0, "NAXIS2"), "2");
+
+        let fits = Fits::open(&path).unwrap();
+        let expected : Vec<f32> = planes.iter().flat_map(|(_, img)| fits_plane(img, &settings)).collect();
+        match fits.get(0).unwrap().read_data() {
+            FitsData::FloatingPoint32(array) => {
+                assert_eq!(array.shape, vec![6, 4, 2]);
+                assert!(array.data == expected);
+            },
+            _ => panic!("the cube is not 32 bit floats")
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journal_round_trips_and_drops_a_torn_line() {
+        let path = std::env::temp_dir().join(format!("journal_test_{}.csv", std::process::id()));
+        let rows = vec![test_row("image_000000_000.fits", None, 3724),
+            test_row("images_0001.npz", Some(7), 3724)];
+        let mut text = String::from("# abc\nfile,plane,sum,max,mean,sd,nonzero\n");
+        for row in &rows { text.push_str(&journal_line(row)); }
+
+        fs::write(&path, &text).unwrap();
+        let journal = read_journal(&path, "abc").unwrap();
+        assert_eq!(journal.len(), 2);
+        assert!(journal[&(String::from("image_000000_000.fits"), None)] == (12.5, 0.125, 0.003, 1e-7, 3724));
+        assert!(journal[&(String::from("images_0001.npz"), Some(7))] == (12.5, 0.125, 0.003, 1e-7, 3724));
+        assert!(read_journal(&path, "abd").is_err());
+
+        // Cut inside the last number, which still parses as 37
+        fs::write(&path, &text[..text.len() - 3]).unwrap();
+        let journal = read_journal(&path, "abc");
+        fs::remove_file(&path).unwrap();
+        let journal = journal.unwrap();
+        assert_eq!(journal.len(), 1);
+        assert!(journal.contains_key(&(String::from("image_000000_000.fits"), None)));
+    }
+
+    #[test]
+    fn the_fingerprint_follows_the_splits() {
+        let settings = test_settings();
+        let mut models = vec![test_model(0, 3, 20), test_model(1, 4, 20)];
+        let unsplit = run_fingerprint(&models, &settings);
+        models[0].split = Some(String::from("train"));
+        models[1].split = Some(String::from("val"));
+        let split = run_fingerprint(&models, &settings);
+        assert_ne!(split, unsplit);
+        // Trading splits changes it too, and the same splits do not
+        models[0].split = Some(String::from("val"));
+        models[1].split = Some(String::from("train"));
+        assert_ne!(run_fingerprint(&models, &settings), split);
+        models[0].split = Some(String::from("train"));
+        models[1].split = Some(String::from("val"));
+        assert_eq!(run_fingerprint(&models, &settings), split);
     }
 }
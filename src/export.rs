@@ -0,0 +1,293 @@
+//! Export images as 8 or 16 bit PNG or TIFF files, for a quick look
+//! or for tools that cannot read FITS.
+
+use std::error::Error;
+use std::fs::File;
+use std::str::FromStr;
+use argparse::{ArgumentParser, Store, StoreOption};
+use image::ColorType;
+use image::png::PngEncoder;
+use tiff::encoder::{TiffEncoder, colortype};
+
+/// The file format to export to.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ExportFormat {
+    Png,
+    Tiff
+}
+
+impl FromStr for ExportFormat {
+    type Err = ();
+    fn from_str(src: &str) -> Result<ExportFormat, ()> {
+        match src {
+            "png" => Ok(ExportFormat::Png),
+            "tiff" | "tif" => Ok(ExportFormat::Tiff),
+            _ => Err(())
+        }
+    }
+}
+
+/// How the float pixels are mapped onto the integers. MinMax stretches
+/// each image from its smallest to its largest pixel, Percentile
+/// stretches each image between two of its percentiles and clips the
+/// rest, and Fixed uses the same range for every image.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Scaling {
+    MinMax,
+    Percentile,
+    Fixed
+}
+
+impl FromStr for Scaling {
+    type Err = ();
+    fn from_str(src: &str) -> Result<Scaling, ()> {
+        match src {
+            "minmax" => Ok(Scaling::MinMax),
+            "percentile" => Ok(Scaling::Percentile),
+            "fixed" => Ok(Scaling::Fixed),
+            _ => Err(())
+        }
+    }
+}
+
+/// The export settings. No format means we do not export. Low and
+/// high are percentiles for Percentile and pixel values for Fixed.
+#[derive(Clone)]
+pub struct ExportSettings {
+    pub format : Option<ExportFormat>,
+    pub bits : u32,
+    pub scaling : Scaling,
+    pub low : Option<f32>,
+    pub high : Option<f32>
+}
+
+impl Default for ExportSettings {
+    fn default() -> ExportSettings {
+        ExportSettings::new()
+    }
+}
+
+impl ExportSettings {
+    pub fn new() -> ExportSettings {
+        ExportSettings {
+            format : None,
+            bits : 16,
+            scaling : Scaling::MinMax,
+            low : None,
+            high : None
+        }
+    }
+
+    /// Returns a Result - an error if the settings do not make sense.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.bits != 8 && self.bits != 16 {
+            return Err(From::from(format!("Export bit depth must be 8 or 16, not {}", self.bits)));
+        }
+        match self.scaling {
+            Scaling::Fixed => {
+                match (self.low, self.high) {
+                    (Some(low), Some(high)) if low < high => Ok(()),
+                    (Some(_), Some(_)) => Err(From::from("The fixed export range needs low below high")),
+                    _ => Err(From::from("Fixed export scaling needs both --export-low and --export-high"))
+                }
+            },
+            Scaling::Percentile => {
+                let (low, high) = self.percentiles();
+                if low >= 0.0 && high <= 100.0 && low < high { Ok(()) } else {
+                    Err(From::from(format!("Bad export percentiles {} and {}", low, high)))
+                }
+            },
+            Scaling::MinMax => Ok(())
+        }
+    }
+
+    /// Returns the str extension of exported files, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            Some(ExportFormat::Tiff) => "tiff",
+            _ => "png"
+        }
+    }
+
+    /// Returns the (f32, f32) percentiles to clip at, 0.5 and 99.5
+    /// unless they are given.
+    fn percentiles(&self) -> (f32, f32) {
+        (self.low.unwrap_or(0.5), self.high.unwrap_or(99.5))
+    }
+}
+
+/// Returns None
+/// Add the export options to a program's argument parser.
+/// # Arguments
+///
+/// * `ap` - An ArgumentParser - the parser of the program
+/// * `settings` - An ExportSettings - where the options go
+///
+pub fn add_options<'a>(ap : &mut ArgumentParser<'a>, settings : &'a mut ExportSettings) {
+    let ExportSettings { format, bits, scaling, low, high } = settings;
+    ap.refer(format)
+        .add_option(&["--export"], StoreOption,
+        "Also write every image as a png or tiff");
+    ap.refer(bits)
+        .add_option(&["--export-bits"], Store,
+        "Bit depth of the exported images, 8 or 16 (default: 16)");
+    ap.refer(scaling)
+        .add_option(&["--export-scaling"], Store,
+        "How pixels map onto the bit depth: minmax, percentile or fixed (default: minmax)");
+    ap.refer(low)
+        .add_option(&["--export-low"], StoreOption,
+        "Lower percentile (default: 0.5) or, for fixed scaling, the pixel value that maps to 0");
+    ap.refer(high)
+        .add_option(&["--export-high"], StoreOption,
+        "Upper percentile (default: 99.5) or, for fixed scaling, the pixel value that maps to the top");
+}
+
+/// Returns an f32 - the value at percentile `p` of sorted values,
+/// interpolating between neighbours.
+/// # Arguments
+///
+/// * `sorted` - A slice of f32 - the values, in order
+/// * `p` - An f32 - the percentile, from 0 to 100
+///
+fn percentile(sorted : &[f32], p : f32) -> f32 {
+    if sorted.is_empty() { return 0.0; }
+    let rank = (p / 100.0) * (sorted.len() - 1) as f32;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f32)
+}
+
+/// Returns a Vec of u16 - the pixels scaled onto 0 to 255 or 0 to
+/// 65535. Pixels that are not numbers become 0.
+/// # Arguments
+///
+/// * `pixels` - A slice of f32 - the pixels
+/// * `settings` - An ExportSettings - the scaling and bit depth
+///
+pub fn scale_pixels(pixels : &[f32], settings : &ExportSettings) -> Vec<u16> {
+    let top = if settings.bits == 8 { 255.0 } else { 65535.0 };
+    let mut finite : Vec<f32> = pixels.iter().cloned().filter(|v| v.is_finite()).collect();
+
+    let (low, high) = match settings.scaling {
+        Scaling::Fixed => (settings.low.unwrap_or(0.0), settings.high.unwrap_or(1.0)),
+        Scaling::MinMax => {
+            let low = finite.iter().cloned().fold(f32::INFINITY, f32::min);
+            let high = finite.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            (low, high)
+        },
+        Scaling::Percentile => {
+            finite.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let (pl, ph) = settings.percentiles();
+            (percentile(&finite, pl), percentile(&finite, ph))
+        }
+    };
+
+    pixels.iter().map(|v| {
+        // A flat image, or a range that is not a number, has nothing to stretch
+        if !v.is_finite() || high.partial_cmp(&low) != Some(std::cmp::Ordering::Greater) { return 0; }
+        let t = ((v - low) / (high - low)).clamp(0.0, 1.0);
+        (t * top).round() as u16
+    }).collect()
+}
+
+/// Returns a Result - write an image as a png or tiff. The image is
+/// indexed [x][y] and written the same way up as the FITS files.
+/// # Arguments
+///
+/// * `img` - A slice of Vec of f32 - the image
+/// * `width` - A usize - the image width
+/// * `height` - A usize - the image height
+/// * `filename` - A str - the file to write
+/// * `settings` - An ExportSettings - the format, depth and scaling
+///
+pub fn save_export(img : &[Vec<f32>], width : usize, height : usize, filename : &str,
+    settings : &ExportSettings) -> Result<(), Box<dyn Error>> {
+    let mut pixels : Vec<f32> = Vec::with_capacity(width * height);
+    for y in 0..height {
+        pixels.extend(img.iter().take(width).map(|column| column[y]));
+    }
+    let scaled = scale_pixels(&pixels, settings);
+    let file = File::create(filename)?;
+
+    match (settings.format, settings.bits) {
+        (Some(ExportFormat::Tiff), 8) => {
+            let bytes : Vec<u8> = scaled.iter().map(|v| *v as u8).collect();
+            TiffEncoder::new(file)?.write_image::<colortype::Gray8>(width as u32, height as u32, &bytes)?;
+        },
+        (Some(ExportFormat::Tiff), _) => {
+            TiffEncoder::new(file)?.write_image::<colortype::Gray16>(width as u32, height as u32, &scaled)?;
+        },
+        (_, 8) => {
+            let bytes : Vec<u8> = scaled.iter().map(|v| *v as u8).collect();
+            PngEncoder::new(file).encode(&bytes, width as u32, height as u32, ColorType::L8)?;
+        },
+        (_, _) => {
+            // PNG wants its 16 bit samples big endian
+            let bytes : Vec<u8> = scaled.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect();
+            PngEncoder::new(file).encode(&bytes, width as u32, height as u32, ColorType::L16)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaling(bits : u32, scaling : Scaling, low : Option<f32>, high : Option<f32>) -> ExportSettings {
+        ExportSettings { format : Some(ExportFormat::Png), bits, scaling, low, high }
+    }
+
+    #[test]
+    fn minmax_fills_the_range() {
+        let pixels = vec![1.0, 2.0, 3.0, 5.0];
+        assert_eq!(scale_pixels(&pixels, &scaling(8, Scaling::MinMax, None, None)), vec![0, 64, 128, 255]);
+        assert_eq!(scale_pixels(&pixels, &scaling(16, Scaling::MinMax, None, None)), vec![0, 16384, 32768, 65535]);
+    }
+
+    #[test]
+    fn percentiles_interpolate_and_clip() {
+        let sorted = vec![0.0, 10.0, 20.0, 30.0];
+        assert_eq!(percentile(&sorted, 0.0), 0.0);
+        assert_eq!(percentile(&sorted, 25.0), 7.5);
+        assert_eq!(percentile(&sorted, 50.0), 15.0);
+        assert_eq!(percentile(&sorted, 100.0), 30.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+
+        // 0 to 100, shuffled, clipped at 10 and 90
+        let pixels : Vec<f32> = (0..101).map(|i| ((i * 37) % 101) as f32).collect();
+        let scaled = scale_pixels(&pixels, &scaling(8, Scaling::Percentile, Some(10.0), Some(90.0)));
+        for (v, s) in pixels.iter().zip(scaled.iter()) {
+            let expected = ((v - 10.0) / 80.0).clamp(0.0, 1.0) * 255.0;
+            assert_eq!(*s, expected.round() as u16, "pixel {}", v);
+        }
+    }
+
+    #[test]
+    fn fixed_range_clamps() {
+        let pixels = vec![-1.0, 0.0, 1.0, 2.0, 3.0];
+        assert_eq!(scale_pixels(&pixels, &scaling(8, Scaling::Fixed, Some(0.0), Some(2.0))), vec![0, 0, 128, 255, 255]);
+    }
+
+    #[test]
+    fn flat_and_bad_pixels_are_zero() {
+        assert_eq!(scale_pixels(&[3.0; 4], &scaling(16, Scaling::MinMax, None, None)), vec![0; 4]);
+        let pixels = vec![f32::NAN, 0.0, f32::INFINITY, 4.0, f32::NEG_INFINITY];
+        assert_eq!(scale_pixels(&pixels, &scaling(8, Scaling::MinMax, None, None)), vec![0, 0, 0, 255, 0]);
+        assert_eq!(scale_pixels(&pixels, &scaling(8, Scaling::Percentile, Some(0.0), Some(100.0))), vec![0, 0, 0, 255, 0]);
+    }
+
+    #[test]
+    fn png_samples_are_big_endian() {
+        let path = std::env::temp_dir().join(format!("export_test_{}.png", std::process::id())).to_string_lossy().to_string();
+        // Indexed [x][y], so this is one row of three
+        let img = vec![vec![258.0], vec![0.0], vec![65535.0]];
+        save_export(&img, 3, 1, &path, &scaling(16, Scaling::Fixed, Some(0.0), Some(65535.0))).unwrap();
+        let read = image::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        match read.unwrap() {
+            image::DynamicImage::ImageLuma16(luma) => assert_eq!(luma.into_raw(), vec![258, 0, 65535]),
+            _ => panic!("not a 16 bit grey png")
+        }
+    }
+}
@@ -0,0 +1,5 @@
+//! Code shared by the render and ilastik programs.
+
+pub mod export;
+pub mod npy;
+pub mod split;
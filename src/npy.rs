@@ -0,0 +1,380 @@
+//! Write images and their metadata as NumPy .npy files and .npz
+//! archives, so the training code can load them without FITS.
+//! Image stacks are C ordered [N, H, W], with the top row first,
+//! the same way up as the FITS files.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The type the image pixels are stored as.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Dtype {
+    Float32,
+    Float16
+}
+
+impl FromStr for Dtype {
+    type Err = ();
+    fn from_str(src: &str) -> Result<Dtype, ()> {
+        match src {
+            "float32" | "f32" => Ok(Dtype::Float32),
+            "float16" | "f16" => Ok(Dtype::Float16),
+            _ => Err(())
+        }
+    }
+}
+
+/// One array, ready to be written - its NumPy type string,
+/// its shape and its little endian bytes.
+pub struct NpyArray {
+    descr : String,
+    shape : Vec<usize>,
+    data : Vec<u8>
+}
+
+impl NpyArray {
+    /// Returns an NpyArray - a stack of images indexed [x][y], laid
+    /// out as [N, H, W] with the top row first: row 0 is y = 0 of the
+    /// image, the top row as the FITS files display. This is flipped
+    /// from `astropy.io.fits.getdata` of the same image, which starts
+    /// with the bottom row, so `np.flipud` of one gives the other.
+    /// # Arguments
+    ///
+    /// * `images` - A slice of images - the stack, in order
+    /// * `width` - A usize - the image width
+    /// * `height` - A usize - the image height
+    /// * `dtype` - A Dtype - float32 or float16
+    ///
+    pub fn images(images : &[&Vec<Vec<f32>>], width : usize, height : usize, dtype : Dtype) -> NpyArray {
+        let size = match dtype { Dtype::Float32 => 4, Dtype::Float16 => 2 };
+        let mut data : Vec<u8> = Vec::with_capacity(images.len() * width * height * size);
+
+        for img in images {
+            for y in 0..height {
+                for x in 0..width {
+                    match dtype {
+                        Dtype::Float32 => data.extend_from_slice(&img[x][y].to_le_bytes()),
+                        Dtype::Float16 => data.extend_from_slice(&f32_to_f16(img[x][y]).to_le_bytes())
+                    }
+                }
+            }
+        }
+
+        NpyArray {
+            descr : String::from(match dtype { Dtype::Float32 => "<f4", Dtype::Float16 => "<f2" }),
+            shape : vec![images.len(), height, width],
+            data
+        }
+    }
+
+    /// Returns an NpyArray - a 1D int64 array.
+    /// # Arguments
+    ///
+    /// * `values` - A slice of i64 - the values
+    ///
+    pub fn ints(values : &[i64]) -> NpyArray {
+        NpyArray {
+            descr : String::from("<i8"),
+            shape : vec![values.len()],
+            data : values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+        }
+    }
+
+    /// Returns an NpyArray - a 1D float32 array.
+    /// # Arguments
+    ///
+    /// * `values` - A slice of f32 - the values
+    ///
+    pub fn reals(values : &[f32]) -> NpyArray {
+        NpyArray {
+            descr : String::from("<f4"),
+            shape : vec![values.len()],
+            data : values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+        }
+    }
+
+    /// Returns an NpyArray - a 1D array of unicode strings, as wide
+    /// as the longest, so it loads without pickle.
+    /// # Arguments
+    ///
+    /// * `values` - A slice of String - the values
+    ///
+    pub fn texts(values : &[String]) -> NpyArray {
+        let width = values.iter().map(|v| v.chars().count()).max().unwrap_or(0).max(1);
+        let mut data : Vec<u8> = Vec::with_capacity(values.len() * width * 4);
+
+        for v in values {
+            let mut n = 0;
+            for c in v.chars() {
+                data.extend_from_slice(&(c as u32).to_le_bytes());
+                n += 1;
+            }
+            for _ in n..width { data.extend_from_slice(&[0, 0, 0, 0]); }
+        }
+
+        NpyArray {
+            descr : format!("<U{}", width),
+            shape : vec![values.len()],
+            data
+        }
+    }
+
+    /// Returns a Vec of u8 - the whole .npy file, version 1.0.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let shape = match self.shape.len() {
+            1 => format!("({},)", self.shape[0]),
+            _ => format!("({})", self.shape.iter().map(|s| s.to_string())
+                .collect::<Vec<String>>().join(", "))
+        };
+        let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            self.descr, shape);
+        // The magic, version and length take 10 bytes and the data
+        // should start on a multiple of 64, after a newline.
+        while (10 + header.len() + 1) % 64 != 0 { header.push(' '); }
+        header.push('\n');
+
+        let mut bytes : Vec<u8> = Vec::with_capacity(10 + header.len() + self.data.len());
+        bytes.extend_from_slice(b"\x93NUMPY");
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+/// Returns a u16 - the bits of the nearest float16, rounding ties to
+/// even. Values too large become infinity and NaN stays NaN.
+/// # Arguments
+///
+/// * `value` - An f32 - the value to convert
+///
+pub fn f32_to_f16(value : f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+
+    if exp == 0xff {
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+
+    let half_exp = exp - 127 + 15;
+    if half_exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    // Keep the top bits of the mantissa and round on the rest. A carry
+    // out of the mantissa moves into the exponent, which is what we want.
+    let (kept, shift) = if half_exp > 0 {
+        (((half_exp as u32) << 10) | (mant >> 13), 13)
+    } else {
+        // Subnormal, the implicit one becomes explicit
+        let shift = (14 - half_exp) as u32;
+        if shift > 24 { return sign; }
+        ((mant | 0x80_0000) >> shift, shift)
+    };
+
+    let full = if half_exp > 0 { mant } else { mant | 0x80_0000 };
+    let rest = full & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let mut half = kept;
+    if rest > halfway || (rest == halfway && (kept & 1) == 1) {
+        half += 1;
+    }
+    sign | half as u16
+}
+
+/// Returns a Result - write one array as a .npy file.
+/// # Arguments
+///
+/// * `array` - An NpyArray - the array
+/// * `filename` - A str - the file to write
+///
+pub fn save_npy(array : &NpyArray, filename : &str) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(filename)?;
+    file.write_all(&array.to_bytes())?;
+    Ok(())
+}
+
+/// Returns a u32 - the CRC-32 of some bytes, as zip files want it.
+/// # Arguments
+///
+/// * `bytes` - A slice of u8 - the bytes
+///
+fn crc32(bytes : &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut c = i as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+
+    let mut crc = 0xffff_ffffu32;
+    for b in bytes {
+        crc = table[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xffff_ffff
+}
+
+/// Returns a Result - write several named arrays as an uncompressed
+/// .npz archive. Each array becomes `<name>.npy` inside the zip.
+/// # Arguments
+///
+/// * `arrays` - A slice of name and NpyArray - the arrays, in order
+/// * `filename` - A str - the file to write
+///
+pub fn save_npz(arrays : &[(String, NpyArray)], filename : &str) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(filename)?;
+    let mut central : Vec<u8> = vec![];
+    let mut offset : u64 = 0;
+
+    for (name, array) in arrays {
+        let name = format!("{}.npy", name);
+        let data = array.to_bytes();
+        if offset + data.len() as u64 > u32::MAX as u64 {
+            return Err(From::from(format!("{} would be over 4GB, which needs zip64 - use smaller chunks", filename)));
+        }
+        let crc = crc32(&data);
+
+        // Version 2.0, no flags, stored, and a date of 1980-01-01
+        let mut common : Vec<u8> = vec![];
+        common.extend_from_slice(&20u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0x21u16.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        let mut local : Vec<u8> = vec![];
+        local.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        local.extend_from_slice(&common);
+        local.extend_from_slice(name.as_bytes());
+        file.write_all(&local)?;
+        file.write_all(&data)?;
+
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes());
+        central.extend_from_slice(&common);
+        // No comment, disk 0, no attributes, then where the entry starts
+        central.extend_from_slice(&[0; 10]);
+        central.extend_from_slice(&(offset as u32).to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+
+        offset += (local.len() + data.len()) as u64;
+    }
+
+    if offset > u32::MAX as u64 {
+        return Err(From::from(format!("{} would be over 4GB, which needs zip64 - use smaller chunks", filename)));
+    }
+
+    let mut end : Vec<u8> = vec![];
+    end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    end.extend_from_slice(&(arrays.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(arrays.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    end.extend_from_slice(&(offset as u32).to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+
+    file.write_all(&central)?;
+    file.write_all(&end)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn float16_rounds_like_numpy() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.5), 0xc100);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(1.0e6), 0x7c00);
+        // The smallest subnormal, and half of it ties to zero
+        assert_eq!(f32_to_f16(5.960_464_5e-8), 0x0001);
+        assert_eq!(f32_to_f16(2.980_232_2e-8), 0x0000);
+        // 1 + 2^-11 is a tie, so it rounds to even
+        assert_eq!(f32_to_f16(1.000_488_3), 0x3c00);
+        assert!(f32_to_f16(f32::NAN) & 0x7fff > 0x7c00);
+    }
+
+    #[test]
+    fn npy_header_is_aligned() {
+        let bytes = NpyArray::ints(&[1, -2, 3]).to_bytes();
+        assert_eq!(&bytes[0..6], b"\x93NUMPY");
+        assert_eq!(&bytes[6..8], &[1, 0]);
+        let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + len) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + len]).unwrap();
+        assert!(header.starts_with("{'descr': '<i8', 'fortran_order': False, 'shape': (3,), }"));
+        assert!(header.ends_with('\n'));
+        assert_eq!(&bytes[10 + len..], &[1, 0, 0, 0, 0, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            3, 0, 0, 0, 0, 0, 0, 0][..]);
+    }
+
+    #[test]
+    fn images_are_n_h_w() {
+        // Two 3 x 2 images, indexed [x][y], with the value 100n + 10y + x
+        let images : Vec<Vec<Vec<f32>>> = (0..2).map(|n| (0..3).map(|x| (0..2).map(|y|
+            (100 * n + 10 * y + x) as f32).collect()).collect()).collect();
+        let stack = NpyArray::images(&images.iter().collect::<Vec<_>>(), 3, 2, Dtype::Float32);
+        assert_eq!(stack.shape, vec![2, 2, 3]);
+        let values : Vec<f32> = stack.data.chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        assert_eq!(values, vec![0.0, 1.0, 2.0, 10.0, 11.0, 12.0, 100.0, 101.0, 102.0, 110.0, 111.0, 112.0]);
+    }
+
+    #[test]
+    fn npz_is_a_stored_zip() {
+        let path = std::env::temp_dir().join(format!("npy_test_{}.npz", std::process::id()));
+        let filename = path.to_string_lossy().to_string();
+        let arrays = vec![(String::from("a"), NpyArray::ints(&[7])),
+            (String::from("bb"), NpyArray::reals(&[0.5, 1.5]))];
+        save_npz(&arrays, &filename).unwrap();
+        let zip = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let u16_at = |i : usize| u16::from_le_bytes([zip[i], zip[i + 1]]) as usize;
+        let u32_at = |i : usize| u32::from_le_bytes([zip[i], zip[i + 1], zip[i + 2], zip[i + 3]]) as usize;
+
+        // The end record says where the central directory is and how many entries
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(end), 0x0605_4b50);
+        assert_eq!(u16_at(end + 10), 2);
+        let mut central = u32_at(end + 16);
+        assert_eq!(central + u32_at(end + 12), end);
+
+        for (name, array) in &arrays {
+            let data = array.to_bytes();
+            let name = format!("{}.npy", name);
+            assert_eq!(u32_at(central), 0x0201_4b50);
+            // Stored, with the right CRC and sizes
+            assert_eq!(u16_at(central + 10), 0);
+            assert_eq!(u32_at(central + 16), crc32(&data) as usize);
+            assert_eq!(u32_at(central + 20), data.len());
+            assert_eq!(u32_at(central + 24), data.len());
+            assert_eq!(&zip[central + 46..central + 46 + name.len()], name.as_bytes());
+
+            let local = u32_at(central + 42);
+            assert_eq!(u32_at(local), 0x0403_4b50);
+            assert_eq!(&zip[local + 14..local + 26], &zip[central + 16..central + 28]);
+            let start = local + 30 + u16_at(local + 26) + u16_at(local + 28);
+            assert_eq!(&zip[start..start + data.len()], &data[..]);
+            central += 46 + name.len();
+        }
+        // The standard check value of CRC-32
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
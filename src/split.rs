@@ -0,0 +1,283 @@
+//! Assign whole models or objects to train, validation and test
+//! splits, so that the renders of one pore never end up on both
+//! sides. The assignment can be written out and read back in to
+//! reuse the same split.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+use argparse::{ArgumentParser, Store, StoreOption};
+use rand::prelude::*;
+use rand::seq::SliceRandom;
+
+/// The names of the splits, in the order the ratios are given.
+pub const SPLIT_NAMES : [&str; 3] = ["train", "val", "test"];
+
+/// Where the split shows up. Column adds it to the manifest and Dirs
+/// puts each split in a directory of its own.
+#[derive(Copy, Clone, PartialEq)]
+pub enum SplitLayout {
+    Column,
+    Dirs
+}
+
+impl FromStr for SplitLayout {
+    type Err = ();
+    fn from_str(src: &str) -> Result<SplitLayout, ()> {
+        match src {
+            "column" => Ok(SplitLayout::Column),
+            "dirs" => Ok(SplitLayout::Dirs),
+            _ => Err(())
+        }
+    }
+}
+
+/// The split settings. With neither ratios nor a file we do not split.
+/// Without a seed, the program falls back on its own.
+#[derive(Clone)]
+pub struct SplitSettings {
+    pub ratios : Option<String>,
+    pub seed : Option<u64>,
+    pub layout : SplitLayout,
+    pub file : Option<String>
+}
+
+impl Default for SplitSettings {
+    fn default() -> SplitSettings {
+        SplitSettings::new()
+    }
+}
+
+impl SplitSettings {
+    pub fn new() -> SplitSettings {
+        SplitSettings {
+            ratios : None,
+            seed : None,
+            layout : SplitLayout::Column,
+            file : None
+        }
+    }
+
+    /// Returns a bool - whether we split at all.
+    pub fn enabled(&self) -> bool {
+        self.ratios.is_some() || self.file.is_some()
+    }
+
+    /// Returns a Result of a Vec of f32 - the ratios, one to three of
+    /// them for train, val and test, scaled to add up to 1.
+    pub fn parse_ratios(&self) -> Result<Vec<f32>, Box<dyn Error>> {
+        let text = self.ratios.clone().unwrap_or_else(|| String::from("0.8,0.1,0.1"));
+        let mut ratios : Vec<f32> = vec![];
+
+        for field in text.split(',') {
+            match field.trim().parse::<f32>() {
+                Ok(r) if r >= 0.0 && r.is_finite() => ratios.push(r),
+                _ => return Err(From::from(format!("Cannot use '{}' as a split ratio", field)))
+            }
+        }
+        let total : f32 = ratios.iter().sum();
+        // A total that is not a number is no good either
+        if ratios.len() > SPLIT_NAMES.len() || total.partial_cmp(&0.0) != Some(std::cmp::Ordering::Greater) {
+            return Err(From::from(format!(
+                "Give one to three split ratios, for train, val and test, that add up to more than 0, not '{}'", text)));
+        }
+        Ok(ratios.iter().map(|r| r / total).collect())
+    }
+
+    /// Returns a Result - an error if the settings do not make sense.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.ratios.is_some() && self.file.is_some() {
+            return Err(From::from("Give either --split or --split-file, not both"));
+        }
+        self.parse_ratios()?;
+        Ok(())
+    }
+}
+
+/// Returns None
+/// Add the split options to a program's argument parser.
+/// # Arguments
+///
+/// * `ap` - An ArgumentParser - the parser of the program
+/// * `settings` - A SplitSettings - where the options go
+///
+pub fn add_options<'a>(ap : &mut ArgumentParser<'a>, settings : &'a mut SplitSettings) {
+    let SplitSettings { ratios, seed, layout, file } = settings;
+    ap.refer(ratios)
+        .add_option(&["--split"], StoreOption,
+        "Split the models or objects into train, val and test with these comma separated ratios, e.g. 0.8,0.1,0.1");
+    ap.refer(seed)
+        .add_option(&["--split-seed"], StoreOption,
+        "Seed for the split, so it can stay put while other seeds change (default: the run's seed, random unless given)");
+    ap.refer(layout)
+        .add_option(&["--split-layout"], Store,
+        "Give the split as a column of the output or as a directory per split: column or dirs (default: column)");
+    ap.refer(file)
+        .add_option(&["--split-file"], StoreOption,
+        "Reuse the split in this file, as written to splits.csv by an earlier run");
+}
+
+/// Returns a Result of a BTreeMap of i64 to String - the split of
+/// every key. Keys are shuffled with the seed and then cut up by the
+/// ratios, so the sizes of the splits are as close as they can be.
+/// # Arguments
+///
+/// * `keys` - A slice of i64 - the models or objects to split
+/// * `settings` - A SplitSettings - the ratios or file
+/// * `seed` - A u64 - the seed for the shuffle
+///
+pub fn assign(keys : &[i64], settings : &SplitSettings, seed : u64) -> Result<BTreeMap<i64, String>, Box<dyn Error>> {
+    if let Some(path) = &settings.file {
+        let fixed = read_splits(path)?;
+        let missing : Vec<String> = keys.iter()
+            .filter(|k| !fixed.contains_key(k)).map(|k| k.to_string()).collect();
+        if !missing.is_empty() {
+            return Err(From::from(format!("{} has no split for {}", path, missing.join(", "))));
+        }
+        return Ok(keys.iter().map(|k| (*k, fixed[k].clone())).collect());
+    }
+
+    let ratios = settings.parse_ratios()?;
+    let mut shuffled = keys.to_vec();
+    shuffled.sort();
+    shuffled.dedup();
+    shuffled.shuffle(&mut StdRng::seed_from_u64(seed));
+
+    let mut splits : BTreeMap<i64, String> = BTreeMap::new();
+    let mut start = 0;
+    let mut cumulative = 0.0;
+    for (s, ratio) in ratios.iter().enumerate() {
+        cumulative += ratio;
+        let end = if s == ratios.len() - 1 {
+            shuffled.len()
+        } else {
+            ((shuffled.len() as f32 * cumulative).round() as usize).min(shuffled.len())
+        };
+        for key in &shuffled[start..end.max(start)] {
+            splits.insert(*key, String::from(SPLIT_NAMES[s]));
+        }
+        start = end.max(start);
+    }
+    Ok(splits)
+}
+
+/// Returns a Vec of String - the names of the splits in use, once
+/// each, with train, val and test first, in that order.
+/// # Arguments
+///
+/// * `names` - A Vec of String - the split of every model or object
+///
+pub fn split_names(names : Vec<String>) -> Vec<String> {
+    let mut names = names;
+    names.sort_by_key(|n| (SPLIT_NAMES.iter().position(|s| s == n).unwrap_or(SPLIT_NAMES.len()), n.clone()));
+    names.dedup();
+    names
+}
+
+/// Returns a Result of a BTreeMap of i64 to String - the splits in
+/// a file written by `write_splits`. Lines that start with # and the
+/// header are skipped.
+/// # Arguments
+///
+/// * `path` - A str - the file to read
+///
+pub fn read_splits(path : &str) -> Result<BTreeMap<i64, String>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let mut splits : BTreeMap<i64, String> = BTreeMap::new();
+
+    for (lidx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let entry = line.trim();
+        if entry.is_empty() || entry.starts_with('#') { continue; }
+        let fields : Vec<&str> = entry.split(',').map(|f| f.trim()).collect();
+        if lidx == 0 && fields[0].parse::<i64>().is_err() { continue; }
+
+        let name_ok = |n : &str| !n.is_empty() && n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        match (fields[0].parse::<i64>(), fields.get(1)) {
+            (Ok(key), Some(name)) if fields.len() == 2 && name_ok(name) => {
+                splits.insert(key, name.to_string());
+            },
+            _ => {
+                return Err(From::from(format!("{} line {}: expected a key and a split name, not '{}'",
+                    path, lidx + 1, entry)));
+            }
+        }
+    }
+    Ok(splits)
+}
+
+/// Returns a Result - write the splits as a two column CSV file
+/// that `--split-file` can read back in.
+/// # Arguments
+///
+/// * `splits` - A BTreeMap of i64 to String - the assignment
+/// * `key_name` - A str - the header of the key column
+/// * `filename` - A Path - the file to write
+///
+pub fn write_splits(splits : &BTreeMap<i64, String>, key_name : &str, filename : &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(filename)?;
+    writeln!(file, "{},split", key_name)?;
+    for (key, name) in splits {
+        writeln!(file, "{},{}", key, name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratios(text : &str) -> SplitSettings {
+        SplitSettings { ratios : Some(String::from(text)), ..SplitSettings::new() }
+    }
+
+    fn count(splits : &BTreeMap<i64, String>, name : &str) -> usize {
+        splits.values().filter(|s| *s == name).count()
+    }
+
+    #[test]
+    fn ratios_cut_to_size() {
+        let keys : Vec<i64> = (0..100).collect();
+        let splits = assign(&keys, &ratios("0.8,0.1,0.1"), 1).unwrap();
+        assert_eq!(splits.len(), 100);
+        assert_eq!((count(&splits, "train"), count(&splits, "val"), count(&splits, "test")), (80, 10, 10));
+        // Scaled to add up to one, and the last split takes what is left
+        let splits = assign(&keys[0..7], &ratios("2,1"), 1).unwrap();
+        assert_eq!((count(&splits, "train"), count(&splits, "val"), count(&splits, "test")), (5, 2, 0));
+        assert!(assign(&keys, &ratios("0.5,0.2,0.2,0.1"), 1).is_err());
+        assert!(assign(&keys, &ratios("0.8,-0.1"), 1).is_err());
+    }
+
+    #[test]
+    fn same_seed_same_split() {
+        let keys : Vec<i64> = (0..50).map(|k| k * 3 - 20).collect();
+        let a = assign(&keys, &ratios("0.6,0.2,0.2"), 42).unwrap();
+        let mut shuffled = keys.clone();
+        shuffled.reverse();
+        assert!(a == assign(&shuffled, &ratios("0.6,0.2,0.2"), 42).unwrap());
+        assert!(a != assign(&keys, &ratios("0.6,0.2,0.2"), 43).unwrap());
+    }
+
+    #[test]
+    fn split_file_round_trips() {
+        let path = std::env::temp_dir().join(format!("split_test_{}.csv", std::process::id()));
+        let keys : Vec<i64> = vec![-4, 3, 9, 12, 30];
+        let splits = assign(&keys, &ratios("0.6,0.2,0.2"), 7).unwrap();
+        write_splits(&splits, "model_id", &path).unwrap();
+
+        let file = SplitSettings { file : Some(path.to_string_lossy().to_string()), ..SplitSettings::new() };
+        assert!(read_splits(file.file.as_ref().unwrap()).unwrap() == splits);
+        // A different seed makes no difference once the file is given
+        assert!(assign(&keys, &file, 99).unwrap() == splits);
+        // and only the keys asked for come back
+        assert_eq!(assign(&keys[1..3], &file, 99).unwrap().len(), 2);
+
+        let missing = assign(&[3, 10, 11], &file, 7);
+        std::fs::remove_file(&path).unwrap();
+        let message = missing.err().unwrap().to_string();
+        assert!(message.ends_with("has no split for 10, 11"), "{}", message);
+    }
+}